@@ -7,12 +7,29 @@
 #include <string>
 #include <iostream>
 #include <map>
+#include <memory>
+#include <mutex>
 
 namespace
 {
 
-std::string _lastError;
-std::map<std::string, DirMonitor*> _monitors;
+thread_local std::string _lastError;
+thread_local int _lastErrorKind = ERROR_KIND_NONE;
+
+std::mutex _monitorsMutex;
+std::map<MonitorHandle, std::shared_ptr<DirMonitor>> _monitors;
+MonitorHandle _nextHandle = 1;
+
+std::shared_ptr<DirMonitor> findMonitor(MonitorHandle handle)
+{
+    std::lock_guard<std::mutex> lock(_monitorsMutex);
+    auto it = _monitors.find(handle);
+    if (it == _monitors.end())
+    {
+        return nullptr;
+    }
+    return it->second;
+}
 
 typedef DWORD SharedData;
 
@@ -133,54 +150,59 @@ std::wstring fromUTF8(const std::string& src)
 }
 
 
-bool Boot(const char* dir)
+MonitorHandle Boot(const char* dir)
 {
     std::cout << "Boot" << std::endl;
     std::wstring dirWide = fromUTF8(dir);
 
-    bool res = true;
+    std::shared_ptr<DirMonitor> newMonitor;
     try
     {
-		DirMonitor* newMonitor = new DirMonitor(dirWide.c_str());
-		_monitors[dir] = newMonitor;
+		newMonitor = std::make_shared<DirMonitor>(dirWide.c_str());
     }
     catch (std::string err)
     {
         _lastError = err;
-        res = false;
+        _lastErrorKind = ERROR_KIND_NONE;
+        return INVALID_MONITOR_HANDLE;
     }
-    return res;
+
+    std::lock_guard<std::mutex> lock(_monitorsMutex);
+    MonitorHandle handle = _nextHandle++;
+    _monitors[handle] = newMonitor;
+    return handle;
 }
 
-bool Tick(const char* dir)
+bool Tick(MonitorHandle handle)
 {
 	bool res = true;
+	std::shared_ptr<DirMonitor> monitor = findMonitor(handle);
+	if (!monitor)
+	{
+		_lastError = "Dir Monitor not found!";
+		_lastErrorKind = ERROR_KIND_HANDLE_LOST;
+		return false;
+	}
 	try
 	{
-		auto it = _monitors.find(dir);
-		if (it == _monitors.end())
-		{
-			throw "Dir Monitor not found!";
-		}
-		DirMonitor* monitor = it->second;
 		monitor->Tick();
 	}
 	catch (std::string err)
 	{
 		_lastError = err;
+		_lastErrorKind = monitor->GetErrorKind();
 		res = false;
 	}
     return res;
 }
 
-const FileSystemEvent* Peek(const char* dir)
+const FileSystemEvent* Peek(MonitorHandle handle)
 {
-	auto it = _monitors.find(dir);
-	if (it == _monitors.end())
+	std::shared_ptr<DirMonitor> monitor = findMonitor(handle);
+	if (!monitor)
 	{
 		return nullptr;
 	}
-	DirMonitor* monitor = it->second;
 
     std::queue<FileSystemEvent>& events = monitor->GetFSEvents();
     if (events.empty())
@@ -191,14 +213,13 @@ const FileSystemEvent* Peek(const char* dir)
     return &events.front();
 }
 
-void Pop(const char* dir)
+void Pop(MonitorHandle handle)
 {
-	auto it = _monitors.find(dir);
-	if (it == _monitors.end())
+	std::shared_ptr<DirMonitor> monitor = findMonitor(handle);
+	if (!monitor)
 	{
 		return;
 	}
-	DirMonitor* monitor = it->second;
 
     std::queue<FileSystemEvent>& events = monitor->GetFSEvents();
     if (!events.empty())
@@ -207,11 +228,20 @@ void Pop(const char* dir)
     }
 }
 
-void Shutdown(const char* dir)
+void Shutdown(MonitorHandle handle)
 {
-	DirMonitor* monitor = _monitors[dir];
-	delete monitor;
-    _monitors.erase(dir);
+	std::shared_ptr<DirMonitor> monitor;
+	{
+		std::lock_guard<std::mutex> lock(_monitorsMutex);
+		auto it = _monitors.find(handle);
+		if (it == _monitors.end())
+		{
+			return;
+		}
+		monitor = std::move(it->second);
+		_monitors.erase(it);
+	}
+	// The monitor is destroyed here, outside of the lock.
 }
 
 const char* GetLastErr(int* len)
@@ -220,12 +250,17 @@ const char* GetLastErr(int* len)
     return _lastError.c_str();
 }
 
+int GetLastErrKind()
+{
+    return _lastErrorKind;
+}
+
 int GetAction(const FileSystemEvent* event)
 {
     return event->_action;
 }
 
-const char* GetFile(const FileSystemEvent* event, int* size)
+const wchar_t* GetFile(const FileSystemEvent* event, int* size)
 {
     *size = event->file.size();
     return event->file.c_str();
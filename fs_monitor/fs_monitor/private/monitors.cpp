@@ -2,6 +2,7 @@
 #include "util.h"
 
 #include <iostream>
+#include <vector>
 
 namespace
 {
@@ -38,6 +39,7 @@ namespace
 #define THROW_CRT_ERR \
 { \
     _thrownError = true; \
+    _errorKind = ERROR_KIND_HANDLE_LOST; \
     _error = GetLastErrorAsString(); \
     co_await _blocker; \
 } \
@@ -45,23 +47,24 @@ namespace
 
 Coroutine DirMonitor::StartMonitoring()
 {
-    const int BUFFER_SIZE = 1024;
-    static BYTE buffer[BUFFER_SIZE];
+    const int BUFFER_SIZE = 64 * 1024;
+    std::vector<BYTE> buffer(BUFFER_SIZE);
     DWORD bytesReturned;
     OVERLAPPED overlapped = {0};
     overlapped.hEvent = _hEvent;
 
     while (true)
     {
-        ZeroMemory(&buffer, sizeof(buffer));
+        ZeroMemory(buffer.data(), buffer.size());
         ResetEvent(overlapped.hEvent);
 
         BOOL result = ReadDirectoryChangesW(
             _hDir,
-            buffer,
-            sizeof(buffer),
+            buffer.data(),
+            static_cast<DWORD>(buffer.size()),
             TRUE, // Watch subdirectories
-            FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME,
+            FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME |
+            FILE_NOTIFY_CHANGE_LAST_WRITE,
 //          FILE_NOTIFY_CHANGE_FILE_NAME | FILE_NOTIFY_CHANGE_DIR_NAME |
 //          FILE_NOTIFY_CHANGE_ATTRIBUTES | FILE_NOTIFY_CHANGE_SIZE |
 //          FILE_NOTIFY_CHANGE_LAST_WRITE,
@@ -87,24 +90,25 @@ Coroutine DirMonitor::StartMonitoring()
         }
 
         if (!GetOverlappedResult(_hDir, &overlapped, &bytesReturned, FALSE)) {
+            if (GetLastError() == ERROR_NOTIFY_ENUM_DIR) {
+                _fsEvents.emplace(FileSystemEvent{ FS_ACTION_OVERFLOW });
+                continue;
+            }
             THROW_CRT_ERR
         }
 
+        if (bytesReturned == 0) {
+            _fsEvents.emplace(FileSystemEvent{ FS_ACTION_OVERFLOW });
+            continue;
+        }
+
         DWORD offset = 0;
         FILE_NOTIFY_INFORMATION* pNotify;
 
         do {
-            pNotify = (FILE_NOTIFY_INFORMATION*)((BYTE*)buffer + offset);
+            pNotify = (FILE_NOTIFY_INFORMATION*)(buffer.data() + offset);
             FileSystemEvent& evt = _fsEvents.emplace(FileSystemEvent{ static_cast<int>(pNotify->Action) });
-
-            static wchar_t buff[MAX_PATH] = {};
-
-            wcsncpy_s(
-                buff,
-                _countof(buff),
-                pNotify->FileName,
-                pNotify->FileNameLength / sizeof(WCHAR));
-            evt.file = toUTF8(buff);
+            evt.file.assign(pNotify->FileName, pNotify->FileNameLength / sizeof(WCHAR));
 
             offset += pNotify->NextEntryOffset;
         } while (pNotify->NextEntryOffset != 0);
@@ -158,6 +162,11 @@ void DirMonitor::Tick()
     }
 }
 
+int DirMonitor::GetErrorKind() const
+{
+    return _errorKind;
+}
+
 std::queue<FileSystemEvent>& DirMonitor::GetFSEvents()
 {
     return _fsEvents;
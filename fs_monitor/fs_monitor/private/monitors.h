@@ -21,6 +21,7 @@ private:
     Coroutine StartMonitoring();
 
     bool _thrownError = false;
+    int _errorKind = ERROR_KIND_NONE;
     std::string _error;
 
 public:
@@ -28,5 +29,6 @@ public:
     ~DirMonitor();
 
     void Tick();
+    int GetErrorKind() const;
     std::queue<FileSystemEvent>& GetFSEvents();
 };
@@ -8,19 +8,33 @@ extern "C"
 	struct FileSystemEvent
 	{
 		int _action;
-		std::string file;
+		std::wstring file;
+	};
+
+	const int FS_ACTION_OVERFLOW = 6;
+
+	enum ErrorKind
+	{
+		ERROR_KIND_NONE = 0,
+		ERROR_KIND_HANDLE_LOST = 2
 	};
 
     DWORD __stdcall GetRunningInstance();
 
 	const char* __stdcall GetLastErr(int* len);
+	int __stdcall GetLastErrKind();
 	int __stdcall GetAction(const FileSystemEvent* event);
-	const char* __stdcall GetFile(const FileSystemEvent* event, int* size);
+	const wchar_t* __stdcall GetFile(const FileSystemEvent* event, int* size);
+
+	// Every Boot returns its own handle, so several owners can watch the same
+	// directory. A handle must only be used from one thread at a time.
+	typedef int MonitorHandle;
+	const MonitorHandle INVALID_MONITOR_HANDLE = 0;
 
-    bool __stdcall Boot(const char* dir);
-    bool __stdcall Tick(const char* dir);
-	const FileSystemEvent* __stdcall Peek(const char* dir);
-	void __stdcall Pop(const char* dir);
-	void __stdcall Shutdown(const char* dir);
+    MonitorHandle __stdcall Boot(const char* dir);
+    bool __stdcall Tick(MonitorHandle handle);
+	const FileSystemEvent* __stdcall Peek(MonitorHandle handle);
+	void __stdcall Pop(MonitorHandle handle);
+	void __stdcall Shutdown(MonitorHandle handle);
 }
 
@@ -1,6 +1,8 @@
 fn main() {
     println!("cargo::rerun-if-changed=lib");
-    println!("cargo:rustc-link-search=native=lib");
-    println!("cargo:rustc-link-lib=static=fs_monitor");
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("windows") {
+        println!("cargo:rustc-link-search=native=lib");
+        println!("cargo:rustc-link-lib=static=fs_monitor");
+    }
     tauri_build::build()
 }
@@ -0,0 +1,153 @@
+use serde_json::{json, Value};
+
+use crate::config::{get_config_layers, load_layers};
+use crate::instance::find_running;
+use crate::ipc::{call, connect, dispatch_saved, RpcError, APP_ERROR};
+use crate::{get_bin_file_path, CliArgs, RegisterOptions, TrackerRegistry};
+
+const USAGE: &str = "Usage:
+    side_bin add [--shelf <name>] <path>...
+    side_bin list [--shelf <name>] [--json]
+    side_bin remove <id>
+    side_bin reveal <id>";
+
+const COMMANDS: [&str; 4] = ["add", "list", "remove", "reveal"];
+
+pub fn is_command(arg: &str) -> bool {
+    COMMANDS.contains(&arg)
+}
+
+#[cfg(target_os = "windows")]
+fn attach_console() {
+    use windows_sys::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+    unsafe {
+        AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn attach_console() {
+}
+
+fn call_offline(args: &CliArgs, method: &str, params: &Value) -> Result<Value, RpcError> {
+    let loaded = load_layers(&get_config_layers(args.config.as_deref()));
+    let options = RegisterOptions {
+        content_hash: loaded.config.content_hash,
+        watch_scope: loaded.config.watch_scope()
+    };
+
+    let registry = match get_bin_file_path(args.profile.as_deref()) {
+        Some(path) => TrackerRegistry::with_persistence(path),
+        None => {
+            return Err(RpcError {
+                code: APP_ERROR,
+                message: "Can't locate the home directory".into()
+            });
+        }
+    };
+    registry.set_slot_limits(loaded.config.slot_limits());
+    dispatch_saved(&registry, &options, &loaded.config.shelf_names(), method, params)
+}
+
+fn state_name(state: &Value) -> String {
+    match state {
+        Value::Object(obj) => obj.keys().next().cloned().unwrap_or_default(),
+        _ => String::new()
+    }
+}
+
+fn print_result(method: &str, result: &Value, as_json: bool) -> i32 {
+    match method {
+        "register" => {
+            let mut code = 0;
+            let results = result["results"].as_array().cloned().unwrap_or_default();
+            for res in results {
+                let file = res["file"].as_str().unwrap_or_default();
+                match res["id"].as_str() {
+                    Some(id) => {
+                        println!("{}\t{}", id, file);
+                    }
+                    None => {
+                        eprintln!("{}: {}", file, res["error"].as_str().unwrap_or_default());
+                        code = 1;
+                    }
+                }
+            }
+            code
+        }
+        "list" => {
+            let entries = result["entries"].as_array().cloned().unwrap_or_default();
+            if as_json {
+                println!("{}", serde_json::to_string_pretty(&entries).unwrap());
+                return 0;
+            }
+            for entry in entries {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    entry["id"].as_str().unwrap_or_default(),
+                    entry["shelf"].as_str().unwrap_or_default(),
+                    state_name(&entry["state"]),
+                    entry["path"].as_str().unwrap_or_default());
+            }
+            0
+        }
+        _ => {
+            0
+        }
+    }
+}
+
+pub fn run(args: &CliArgs) -> Option<i32> {
+    let (command, rest) = args.positional.split_first()?;
+
+    let mut as_json = false;
+    let (method, params) = match (command.as_str(), rest) {
+        ("add", paths) if !paths.is_empty() => {
+            let paths: Vec<String> = paths.iter()
+                .map(|x| {
+                    std::path::absolute(x)
+                        .map(|x| x.to_string_lossy().into_owned())
+                        .unwrap_or(x.to_owned())
+                })
+                .collect();
+            ("register", json!({ "paths": paths, "shelf": args.shelf }))
+        }
+        ("list", flags) if flags.iter().all(|x| x == "--json") => {
+            as_json = !flags.is_empty();
+            ("list", json!({ "shelf": args.shelf }))
+        }
+        ("remove", [id]) => ("unregister", json!({ "id": id })),
+        ("reveal", [id]) => ("reveal", json!({ "id": id })),
+        ("add" | "list" | "remove" | "reveal", _) => {
+            attach_console();
+            eprintln!("{}", USAGE);
+            return Some(2);
+        }
+        _ => {
+            return None;
+        }
+    };
+
+    attach_console();
+    let res = match find_running(args.profile.as_deref()) {
+        Some(instance) => {
+            connect(&instance.socket)
+                .and_then(|conn| call(conn, method, params))
+                .unwrap_or_else(|err| {
+                    Err(RpcError {
+                        code: APP_ERROR,
+                        message: format!("The running instance (PID {}) did not respond: {}", instance.pid, err)
+                    })
+                })
+        }
+        None => call_offline(args, method, &params)
+    };
+
+    match res {
+        Ok(result) => Some(print_result(method, &result, as_json)),
+        Err(error) => {
+            eprintln!("{}", error.message);
+            Some(1)
+        }
+    }
+}
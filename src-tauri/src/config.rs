@@ -0,0 +1,647 @@
+use std::{collections::{BTreeMap, HashMap}, path::{Path, PathBuf}};
+
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+
+use crate::{GracePeriods, SearchOptions, WatchBackend, WatchOptions, WatchScope, DEFAULT_SHELF};
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum WatchScopeSetting {
+    Levels(usize),
+    Named(String)
+}
+
+#[cfg(target_os = "linux")]
+const DEFAULT_WATCH_SCOPE: &str = "parent";
+
+#[cfg(not(target_os = "linux"))]
+const DEFAULT_WATCH_SCOPE: &str = "volume";
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShelfConfig {
+    pub collapsed: Option<[f64; 2]>,
+    pub expanded: Option<[f64; 2]>,
+    pub anchor: Option<[f64; 2]>,
+    pub position: Option<[f64; 2]>,
+    pub max_slots: Option<usize>
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    pub collapsed: [f64; 2],
+    pub expanded: [f64; 2],
+    pub anchor: [f64; 2],
+    pub position: [f64; 2],
+    pub name_limit: usize,
+    pub max_slots: usize,
+    pub content_hash: bool,
+    pub watch_scope: WatchScopeSetting,
+    pub lost_grace_period: u64,
+    pub deleted_grace_period: u64,
+    pub recovery_roots: Vec<String>,
+    pub watch_backend: String,
+    pub poll_interval: u64,
+    pub widen_levels: usize,
+    pub search_limit: usize,
+    pub shelves: BTreeMap<String, ShelfConfig>
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            collapsed: [20.0, 20.0],
+            expanded: [100.0, 300.0],
+            anchor: [0.0, 0.0],
+            position: [0.0, 0.0],
+            name_limit: 30,
+            max_slots: 3,
+            content_hash: false,
+            watch_scope: WatchScopeSetting::Named(DEFAULT_WATCH_SCOPE.into()),
+            lost_grace_period: 1000,
+            deleted_grace_period: 30000,
+            recovery_roots: vec![],
+            watch_backend: "auto".into(),
+            poll_interval: 2000,
+            widen_levels: 3,
+            search_limit: 20_000,
+            shelves: BTreeMap::new()
+        }
+    }
+}
+
+impl Config {
+    pub fn watch_scope(&self) -> WatchScope {
+        match &self.watch_scope {
+            WatchScopeSetting::Levels(levels) => WatchScope::Ancestors(*levels),
+            WatchScopeSetting::Named(name) if name == "parent" => WatchScope::Parent,
+            WatchScopeSetting::Named(_) => WatchScope::Volume
+        }
+    }
+
+    pub fn watch_options(&self) -> WatchOptions {
+        let backend = match self.watch_backend.as_str() {
+            "native" => WatchBackend::Native,
+            "polling" => WatchBackend::Polling,
+            _ => WatchBackend::Auto
+        };
+        WatchOptions {
+            backend,
+            poll_interval: std::time::Duration::from_millis(self.poll_interval),
+            ..WatchOptions::default()
+        }
+    }
+
+    pub fn search_options(&self) -> SearchOptions {
+        SearchOptions {
+            widen_levels: self.widen_levels,
+            search_limit: self.search_limit
+        }
+    }
+
+    pub fn grace_periods(&self) -> GracePeriods {
+        GracePeriods {
+            lost: std::time::Duration::from_millis(self.lost_grace_period),
+            deleted: std::time::Duration::from_millis(self.deleted_grace_period)
+        }
+    }
+
+    pub fn shelf_names(&self) -> Vec<String> {
+        let mut names = vec![DEFAULT_SHELF.to_owned()];
+        for name in self.shelves.keys() {
+            if name != DEFAULT_SHELF {
+                names.push(name.to_owned());
+            }
+        }
+        names
+    }
+
+    pub fn slot_limits(&self) -> HashMap<String, usize> {
+        self.shelf_names().into_iter()
+            .map(|shelf| {
+                let max_slots = self.for_shelf(&shelf).max_slots;
+                (shelf, max_slots)
+            })
+            .collect()
+    }
+
+    pub fn for_shelf(&self, shelf: &str) -> Config {
+        let mut config = self.to_owned();
+        if let Some(overrides) = self.shelves.get(shelf) {
+            config.collapsed = overrides.collapsed.unwrap_or(config.collapsed);
+            config.expanded = overrides.expanded.unwrap_or(config.expanded);
+            config.anchor = overrides.anchor.unwrap_or(config.anchor);
+            config.position = overrides.position.unwrap_or(config.position);
+            config.max_slots = overrides.max_slots.unwrap_or(config.max_slots);
+        }
+        config
+    }
+}
+
+pub fn is_valid_shelf_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ConfigIssue {
+    pub key: String,
+    pub message: String
+}
+
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub errors: Vec<ConfigIssue>,
+    pub warnings: Vec<ConfigIssue>,
+    pub sources: BTreeMap<String, String>
+}
+
+impl LoadedConfig {
+    fn error(&mut self, key: &str, message: String) {
+        self.errors.push(ConfigIssue {
+            key: key.into(),
+            message
+        });
+    }
+
+    fn warning(&mut self, key: &str, message: String) {
+        self.warnings.push(ConfigIssue {
+            key: key.into(),
+            message
+        });
+    }
+
+    fn read_field<T: DeserializeOwned>(&mut self, obj: &Map<String, Value>, key: &str, field: &mut T) {
+        self.read_field_at(obj, key, key, field);
+    }
+
+    fn read_field_at<T: DeserializeOwned>(&mut self, obj: &Map<String, Value>, key: &str, issue_key: &str, field: &mut T) {
+        let value = match obj.get(key) {
+            Some(value) => value,
+            None => {
+                return;
+            }
+        };
+        match serde_json::from_value(value.to_owned()) {
+            Ok(value) => {
+                *field = value;
+            }
+            Err(err) => {
+                self.error(issue_key, format!("Invalid value {}: {}", value, err));
+            }
+        }
+    }
+
+    fn check_size(&mut self, key: &str, size: [f64; 2], default: [f64; 2]) -> [f64; 2] {
+        if size.iter().any(|x| *x < 0.0) {
+            self.error(key, format!("Sizes must be non-negative, got {:?}", size));
+            return default;
+        }
+        size
+    }
+
+    fn check_anchor(&mut self, key: &str, anchor: [f64; 2], default: [f64; 2]) -> [f64; 2] {
+        if anchor.iter().any(|x| !(0.0..=1.0).contains(x)) {
+            self.error(key, format!("Anchor must be within [0, 1], got {:?}", anchor));
+            return default;
+        }
+        anchor
+    }
+
+    fn check_max_slots(&mut self, key: &str, max_slots: usize, default: usize) -> usize {
+        if max_slots == 0 {
+            self.error(key, "The bin needs at least one slot".into());
+            return default;
+        }
+        max_slots
+    }
+
+    fn validate(&mut self) {
+        let default = Config::default();
+
+        self.config.collapsed = self.check_size("collapsed", self.config.collapsed, default.collapsed);
+        self.config.expanded = self.check_size("expanded", self.config.expanded, default.expanded);
+        self.config.anchor = self.check_anchor("anchor", self.config.anchor, default.anchor);
+        self.config.max_slots = self.check_max_slots("maxSlots", self.config.max_slots, default.max_slots);
+
+        if let WatchScopeSetting::Named(name) = &self.config.watch_scope {
+            if name != "parent" && name != "volume" {
+                let message = format!("Expected \"parent\", \"volume\" or a number, got \"{}\"", name);
+                self.error("watchScope", message);
+                self.config.watch_scope = default.watch_scope;
+            }
+            else if name == "volume" && cfg!(target_os = "linux") {
+                self.warning("watchScope", "\"volume\" isn't supported on Linux, using \"parent\"".into());
+                self.config.watch_scope = default.watch_scope;
+            }
+        }
+
+        let watch_backend = &self.config.watch_backend;
+        if !["auto", "native", "polling"].contains(&watch_backend.as_str()) {
+            let message = format!("Expected \"auto\", \"native\" or \"polling\", got \"{}\"", watch_backend);
+            self.error("watchBackend", message);
+            self.config.watch_backend = default.watch_backend;
+        }
+
+        if self.config.poll_interval == 0 {
+            self.error("pollInterval", "The poll interval must be positive".into());
+            self.config.poll_interval = default.poll_interval;
+        }
+
+        if self.config.search_limit == 0 {
+            self.error("searchLimit", "The search limit must be positive".into());
+            self.config.search_limit = default.search_limit;
+        }
+    }
+
+    fn read_shelf(&mut self, name: &str, value: &Value) -> Option<ShelfConfig> {
+        let prefix = format!("shelves.{}", name);
+        if !is_valid_shelf_name(name) {
+            self.error(&prefix, format!("Invalid shelf name \"{}\": use letters, digits, '-' and '_'", name));
+            return None;
+        }
+        let obj = match value {
+            Value::Object(obj) => obj,
+            _ => {
+                self.error(&prefix, format!("Expected an object, got {}", value));
+                return None;
+            }
+        };
+        for key in obj.keys() {
+            if !KNOWN_SHELF_KEYS.contains(&key.as_str()) {
+                self.warning(&format!("{}.{}", prefix, key), format!("Unknown key \"{}\"", key));
+            }
+        }
+
+        let key = |name: &str| format!("{}.{}", prefix, name);
+        let mut shelf = ShelfConfig::default();
+        self.read_field_at(obj, "collapsed", &key("collapsed"), &mut shelf.collapsed);
+        self.read_field_at(obj, "expanded", &key("expanded"), &mut shelf.expanded);
+        self.read_field_at(obj, "anchor", &key("anchor"), &mut shelf.anchor);
+        self.read_field_at(obj, "position", &key("position"), &mut shelf.position);
+        self.read_field_at(obj, "maxSlots", &key("maxSlots"), &mut shelf.max_slots);
+
+        let base = self.config.to_owned();
+        shelf.collapsed = shelf.collapsed.map(|x| self.check_size(&key("collapsed"), x, base.collapsed));
+        shelf.expanded = shelf.expanded.map(|x| self.check_size(&key("expanded"), x, base.expanded));
+        shelf.anchor = shelf.anchor.map(|x| self.check_anchor(&key("anchor"), x, base.anchor));
+        shelf.max_slots = shelf.max_slots.map(|x| self.check_max_slots(&key("maxSlots"), x, base.max_slots));
+        Some(shelf)
+    }
+
+    fn read_shelves(&mut self, obj: &Map<String, Value>) {
+        let shelves = match obj.get("shelves") {
+            Some(Value::Object(shelves)) => shelves,
+            Some(value) => {
+                self.error("shelves", format!("Expected an object of shelves, got {}", value));
+                return;
+            }
+            None => {
+                return;
+            }
+        };
+        for (name, value) in shelves.iter() {
+            if let Some(shelf) = self.read_shelf(name, value) {
+                self.config.shelves.insert(name.to_owned(), shelf);
+            }
+        }
+    }
+}
+
+const KNOWN_SHELF_KEYS: &[&str] = &[
+    "collapsed",
+    "expanded",
+    "anchor",
+    "position",
+    "maxSlots"
+];
+
+const KNOWN_KEYS: &[&str] = &[
+    "collapsed",
+    "expanded",
+    "anchor",
+    "position",
+    "nameLimit",
+    "maxSlots",
+    "contentHash",
+    "watchScope",
+    "lostGracePeriod",
+    "deletedGracePeriod",
+    "recoveryRoots",
+    "watchBackend",
+    "pollInterval",
+    "widenLevels",
+    "searchLimit",
+    "shelves"
+];
+
+fn parse_config_value(value: Value) -> LoadedConfig {
+    let mut loaded = LoadedConfig::default();
+    let obj = match value {
+        Value::Object(obj) => obj,
+        _ => {
+            loaded.error("", "The config must be a JSON object".into());
+            return loaded;
+        }
+    };
+    for key in obj.keys() {
+        if !KNOWN_KEYS.contains(&key.as_str()) {
+            loaded.warning(key, format!("Unknown key \"{}\"", key));
+        }
+    }
+
+    let mut config = Config::default();
+    loaded.read_field(&obj, "collapsed", &mut config.collapsed);
+    loaded.read_field(&obj, "expanded", &mut config.expanded);
+    loaded.read_field(&obj, "anchor", &mut config.anchor);
+    loaded.read_field(&obj, "position", &mut config.position);
+    loaded.read_field(&obj, "nameLimit", &mut config.name_limit);
+    loaded.read_field(&obj, "maxSlots", &mut config.max_slots);
+    loaded.read_field(&obj, "contentHash", &mut config.content_hash);
+    loaded.read_field(&obj, "watchScope", &mut config.watch_scope);
+    loaded.read_field(&obj, "lostGracePeriod", &mut config.lost_grace_period);
+    loaded.read_field(&obj, "deletedGracePeriod", &mut config.deleted_grace_period);
+    loaded.read_field(&obj, "recoveryRoots", &mut config.recovery_roots);
+    loaded.read_field(&obj, "watchBackend", &mut config.watch_backend);
+    loaded.read_field(&obj, "pollInterval", &mut config.poll_interval);
+    loaded.read_field(&obj, "widenLevels", &mut config.widen_levels);
+    loaded.read_field(&obj, "searchLimit", &mut config.search_limit);
+    loaded.config = config;
+
+    loaded.validate();
+    loaded.read_shelves(&obj);
+    loaded
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigLayer {
+    pub name: &'static str,
+    pub path: PathBuf
+}
+
+#[cfg(target_os = "windows")]
+fn get_system_config_dir() -> Option<PathBuf> {
+    let program_data = std::env::var_os("ProgramData")?;
+    Some(PathBuf::from(program_data).join("side_bin"))
+}
+
+#[cfg(target_os = "macos")]
+fn get_system_config_dir() -> Option<PathBuf> {
+    Some(PathBuf::from("/Library/Application Support/side_bin"))
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn get_system_config_dir() -> Option<PathBuf> {
+    Some(PathBuf::from("/etc/side_bin"))
+}
+
+#[cfg(target_os = "linux")]
+fn get_xdg_config_dir() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env_home::env_home_dir().map(|x| x.join(".config")))?;
+    Some(config_home.join("side_bin"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_xdg_config_dir() -> Option<PathBuf> {
+    None
+}
+
+pub fn get_user_config_dir() -> Option<PathBuf> {
+    Some(env_home::env_home_dir()?.join(".side_bin"))
+}
+
+pub fn get_exe_config_dir() -> Option<PathBuf> {
+    let current_exe = std::env::current_exe().ok()?;
+    Some(current_exe.parent()?.to_path_buf())
+}
+
+pub fn get_config_dirs() -> Vec<(&'static str, PathBuf)> {
+    let dirs = [
+        ("system", get_system_config_dir()),
+        ("user", get_user_config_dir()),
+        ("xdg", get_xdg_config_dir()),
+        ("exe", get_exe_config_dir())
+    ];
+    dirs.into_iter()
+        .filter_map(|(name, dir)| dir.map(|dir| (name, dir)))
+        .collect()
+}
+
+pub fn get_config_layers(cli_config: Option<&Path>) -> Vec<ConfigLayer> {
+    let mut layers: Vec<ConfigLayer> = get_config_dirs().into_iter()
+        .map(|(name, dir)| {
+            ConfigLayer {
+                name,
+                path: dir.join("config.json")
+            }
+        })
+        .collect();
+
+    if let Some(env_config) = std::env::var_os("SIDE_BIN_CONFIG").filter(|x| !x.is_empty()) {
+        layers.push(ConfigLayer {
+            name: "env",
+            path: env_config.into()
+        });
+    }
+    if let Some(cli_config) = cli_config {
+        layers.push(ConfigLayer {
+            name: "cli",
+            path: cli_config.to_path_buf()
+        });
+    }
+    layers
+}
+
+fn clear_sources(sources: &mut BTreeMap<String, String>, prefix: &str) {
+    let nested = format!("{}.", prefix);
+    sources.retain(|key, _| key != prefix && !key.starts_with(&nested));
+}
+
+fn merge_value(target: &mut Value, layer: Value, prefix: &str, source: &str, sources: &mut BTreeMap<String, String>) {
+    match layer {
+        Value::Object(layer) => {
+            if !target.is_object() {
+                clear_sources(sources, prefix);
+                *target = Value::Object(Map::new());
+            }
+            let target = target.as_object_mut().unwrap();
+            for (key, value) in layer {
+                let path = match prefix.is_empty() {
+                    true => key.to_owned(),
+                    false => format!("{}.{}", prefix, key)
+                };
+                let entry = target.entry(key).or_insert(Value::Null);
+                merge_value(entry, value, &path, source, sources);
+            }
+        }
+        layer => {
+            clear_sources(sources, prefix);
+            sources.insert(prefix.into(), source.into());
+            *target = layer;
+        }
+    }
+}
+
+pub fn load_layers(layers: &[ConfigLayer]) -> LoadedConfig {
+    let mut merged = Value::Object(Map::new());
+    let mut sources = BTreeMap::new();
+    let mut layer_errors = vec![];
+
+    for layer in layers.iter() {
+        let content = match std::fs::read_to_string(&layer.path) {
+            Ok(content) => content,
+            Err(_) => {
+                continue;
+            }
+        };
+        let value: Value = match serde_json::from_str(&content) {
+            Ok(value @ Value::Object(_)) => value,
+            Ok(_) => {
+                layer_errors.push(ConfigIssue {
+                    key: "".into(),
+                    message: format!("{}: The config must be a JSON object", layer.path.display())
+                });
+                continue;
+            }
+            Err(err) => {
+                layer_errors.push(ConfigIssue {
+                    key: "".into(),
+                    message: format!("{}: Invalid JSON: {}", layer.path.display(), err)
+                });
+                continue;
+            }
+        };
+        merge_value(&mut merged, value, "", &layer.path.to_string_lossy(), &mut sources);
+    }
+
+    let mut loaded = parse_config_value(merged);
+    loaded.errors.splice(0..0, layer_errors);
+    loaded.sources = sources;
+    loaded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn invalid_values_fall_back_to_defaults() {
+        let loaded = parse_config_value(serde_json::json!({
+            "collapsed": [-1, 20],
+            "anchor": [0.5, 2],
+            "maxSlots": 0,
+            "nameLimit": "long",
+            "watchBackend": "fast",
+            "pollInterval": 0,
+            "searchLimit": 0,
+            "watchScope": "far",
+            "expanded": [400, 200],
+            "colour": "red"
+        }));
+        let default = Config::default();
+        let mut keys: Vec<&str> = loaded.errors.iter().map(|x| x.key.as_str()).collect();
+        keys.sort();
+        assert_eq!(keys, ["anchor", "collapsed", "maxSlots", "nameLimit", "pollInterval", "searchLimit", "watchBackend", "watchScope"]);
+        assert_eq!(loaded.warnings.len(), 1);
+        assert_eq!(loaded.warnings[0].key, "colour");
+
+        assert_eq!(loaded.config.collapsed, default.collapsed);
+        assert_eq!(loaded.config.anchor, default.anchor);
+        assert_eq!(loaded.config.max_slots, default.max_slots);
+        assert_eq!(loaded.config.name_limit, default.name_limit);
+        assert_eq!(loaded.config.watch_backend, default.watch_backend);
+        assert_eq!(loaded.config.poll_interval, default.poll_interval);
+        assert_eq!(loaded.config.search_limit, default.search_limit);
+        assert_eq!(loaded.config.watch_scope, default.watch_scope);
+        assert_eq!(loaded.config.expanded, [400.0, 200.0]);
+    }
+
+    #[test]
+    fn config_must_be_an_object() {
+        let loaded = parse_config_value(serde_json::json!([1, 2]));
+        assert_eq!(loaded.errors.len(), 1);
+        assert_eq!(loaded.config, Config::default());
+    }
+
+    #[test]
+    fn shelves_override_the_base_config() {
+        let loaded = parse_config_value(serde_json::json!({
+            "maxSlots": 6,
+            "anchor": [1, 0],
+            "shelves": {
+                "work": { "maxSlots": 2, "expanded": [-5, 10], "colour": "red" },
+                "bad name": {},
+                "notes": 3
+            }
+        }));
+        let mut errors: Vec<&str> = loaded.errors.iter().map(|x| x.key.as_str()).collect();
+        errors.sort();
+        assert_eq!(errors, ["shelves.bad name", "shelves.notes", "shelves.work.expanded"]);
+        assert_eq!(loaded.warnings.len(), 1);
+        assert_eq!(loaded.warnings[0].key, "shelves.work.colour");
+        assert_eq!(loaded.config.shelf_names(), [DEFAULT_SHELF, "work"]);
+
+        let work = loaded.config.for_shelf("work");
+        assert_eq!(work.max_slots, 2);
+        assert_eq!(work.anchor, [1.0, 0.0]);
+        assert_eq!(work.expanded, Config::default().expanded);
+        assert_eq!(loaded.config.for_shelf("other"), loaded.config);
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let dir = temp_dir("config-layers");
+        let layer = |name: &'static str, content: &str| {
+            let path = dir.join(format!("{}.json", name));
+            std::fs::write(&path, content).unwrap();
+            ConfigLayer { name, path }
+        };
+        let layers = [
+            layer("system", r#"{ "maxSlots": 5, "shelves": { "work": { "maxSlots": 2, "anchor": [1, 1] } } }"#),
+            layer("user", r#"{ "shelves": { "work": { "maxSlots": 4 } } }"#),
+            layer("env", "{ not json"),
+            ConfigLayer { name: "cli", path: dir.join("missing.json") }
+        ];
+
+        let loaded = load_layers(&layers);
+        assert_eq!(loaded.errors.len(), 1);
+        assert_eq!(loaded.errors[0].key, "");
+        assert_eq!(loaded.config.max_slots, 5);
+        let work = &loaded.config.shelves["work"];
+        assert_eq!(work.max_slots, Some(4));
+        assert_eq!(work.anchor, Some([1.0, 1.0]));
+
+        let source = |path: &Path| path.to_string_lossy().into_owned();
+        assert_eq!(loaded.sources["maxSlots"], source(&layers[0].path));
+        assert_eq!(loaded.sources["shelves.work.anchor"], source(&layers[0].path));
+        assert_eq!(loaded.sources["shelves.work.maxSlots"], source(&layers[1].path));
+    }
+
+    #[test]
+    fn scalar_layer_replaces_nested_sources() {
+        let mut merged = Value::Object(Map::new());
+        let mut sources = BTreeMap::new();
+        merge_value(&mut merged, serde_json::json!({ "shelves": { "a": { "maxSlots": 1 } } }), "", "first", &mut sources);
+        merge_value(&mut merged, serde_json::json!({ "shelves": 7 }), "", "second", &mut sources);
+
+        assert_eq!(merged, serde_json::json!({ "shelves": 7 }));
+        assert_eq!(sources.len(), 1);
+        assert_eq!(sources["shelves"], "second");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn volume_scope_falls_back_on_linux() {
+        let loaded = parse_config_value(serde_json::json!({ "watchScope": "volume" }));
+        assert!(loaded.errors.is_empty());
+        assert_eq!(loaded.warnings.len(), 1);
+        assert_eq!(loaded.config.watch_scope(), WatchScope::Parent);
+        assert_eq!(Config::default().watch_scope(), WatchScope::Parent);
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::{Read, Seek, SeekFrom};
+
+const BLOCK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ContentHash {
+    pub size: u64,
+    pub hash: String
+}
+
+fn read_block(file: &mut std::fs::File, offset: u64, hasher: &mut blake3::Hasher) -> Option<()> {
+    let mut block = Vec::with_capacity(BLOCK_SIZE as usize);
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.by_ref().take(BLOCK_SIZE).read_to_end(&mut block).ok()?;
+    hasher.update(&block);
+    Some(())
+}
+
+pub fn get_content_hash(file: &str) -> Option<ContentHash> {
+    let mut file = std::fs::File::open(file).ok()?;
+    let meta = file.metadata().ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+
+    let size = meta.len();
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&size.to_le_bytes());
+
+    read_block(&mut file, 0, &mut hasher)?;
+    if size > BLOCK_SIZE {
+        let offset = std::cmp::max(BLOCK_SIZE, size - BLOCK_SIZE);
+        read_block(&mut file, offset, &mut hasher)?;
+    }
+
+    Some(ContentHash {
+        size,
+        hash: hasher.finalize().to_hex().to_string()
+    })
+}
+
+impl ContentHash {
+    pub fn matches(&self, file: &str) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let size = std::fs::metadata(file).map(|x| x.len());
+        if !size.is_ok_and(|x| x == self.size) {
+            return false;
+        }
+        get_content_hash(file).is_some_and(|x| x.eq(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn matches_same_content_only() {
+        let dir = temp_dir("hash");
+        let file = |name: &str, content: &[u8]| {
+            let path = dir.join(name);
+            std::fs::write(&path, content).unwrap();
+            path.to_string_lossy().into_owned()
+        };
+
+        let big = vec![7u8; 3 * BLOCK_SIZE as usize];
+        let mut big_changed = big.to_owned();
+        big_changed[BLOCK_SIZE as usize / 2] = 8;
+        let original = get_content_hash(&file("a", &big)).unwrap();
+        assert!(original.matches(&file("copy", &big)));
+        assert!(!original.matches(&file("changed", &big_changed)));
+        assert!(!original.matches(&file("short", &big[1..])));
+        assert!(!original.matches(&dir.join("missing").to_string_lossy()));
+
+        let empty = get_content_hash(&file("empty", b"")).unwrap();
+        assert!(!empty.matches(&file("other_empty", b"")));
+    }
+}
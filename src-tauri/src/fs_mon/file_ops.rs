@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+
+use super::file_tag::{remove_tag, MARKER_NAME};
+
+fn get_target_path(file: &Path, dir: &Path) -> Result<PathBuf, std::io::Error> {
+    let name = file.file_name()
+        .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The path has no file name"))?;
+    if !dir.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotADirectory,
+            format!("{} is not a directory", dir.display())));
+    }
+
+    let target = dir.join(name);
+    if target.symlink_metadata().is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", target.display())));
+    }
+    Ok(target)
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    let target = std::fs::read_link(src)?;
+    std::os::unix::fs::symlink(target, dest)
+}
+
+#[cfg(target_os = "windows")]
+fn copy_symlink(src: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    let target = std::fs::read_link(src)?;
+    match src.is_dir() {
+        true => std::os::windows::fs::symlink_dir(target, dest),
+        false => std::os::windows::fs::symlink_file(target, dest)
+    }
+}
+
+fn create_target(src: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    let file_type = src.symlink_metadata()?.file_type();
+    if file_type.is_symlink() {
+        return copy_symlink(src, dest);
+    }
+    if file_type.is_dir() {
+        return std::fs::create_dir(dest);
+    }
+    std::fs::File::options().write(true).create_new(true).open(dest)?;
+    Ok(())
+}
+
+fn fill_target(src: &Path, dest: &Path) -> Result<(), std::io::Error> {
+    let file_type = src.symlink_metadata()?.file_type();
+    if file_type.is_symlink() {
+        return Ok(());
+    }
+    if !file_type.is_dir() {
+        std::fs::copy(src, dest)?;
+        return Ok(());
+    }
+
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        if entry.file_name() == MARKER_NAME {
+            continue;
+        }
+        let child = dest.join(entry.file_name());
+        create_target(&entry.path(), &child)?;
+        fill_target(&entry.path(), &child)?;
+    }
+    Ok(())
+}
+
+fn remove_recursive(path: &Path) -> Result<(), std::io::Error> {
+    let file_type = path.symlink_metadata()?.file_type();
+    match file_type.is_dir() {
+        true => std::fs::remove_dir_all(path),
+        false => std::fs::remove_file(path)
+    }
+}
+
+fn copy_to_target(file: &Path, target: &Path) -> Result<(), std::io::Error> {
+    create_target(file, target)?;
+    let res = fill_target(file, target);
+    if res.is_err() {
+        let _ = remove_recursive(target);
+    }
+    res
+}
+
+pub fn copy_to_dir(file: &str, dir: &str) -> Result<String, std::io::Error> {
+    let target = get_target_path(Path::new(file), Path::new(dir))?;
+    copy_to_target(Path::new(file), &target)?;
+
+    let target = target.to_string_lossy().into_owned();
+    remove_tag(&target);
+    Ok(target)
+}
+
+#[derive(Debug)]
+pub enum MoveError {
+    Failed(std::io::Error),
+    SourceLeft {
+        target: String,
+        error: std::io::Error
+    }
+}
+
+impl From<std::io::Error> for MoveError {
+    fn from(err: std::io::Error) -> Self {
+        MoveError::Failed(err)
+    }
+}
+
+fn move_path(file: &Path, target: &Path) -> Result<(), MoveError> {
+    match std::fs::rename(file, target) {
+        Err(err) if err.kind() == std::io::ErrorKind::CrossesDevices => {
+            copy_to_target(file, target)?;
+            remove_recursive(file).map_err(|error| {
+                MoveError::SourceLeft {
+                    target: target.to_string_lossy().into_owned(),
+                    error
+                }
+            })
+        }
+        res => Ok(res?)
+    }
+}
+
+pub fn move_to_dir(file: &str, dir: &str) -> Result<String, MoveError> {
+    let target = get_target_path(Path::new(file), Path::new(dir))?;
+    move_path(Path::new(file), &target)?;
+    Ok(target.to_string_lossy().into_owned())
+}
+
+pub fn rename_in_place(file: &str, name: &str) -> Result<String, std::io::Error> {
+    let valid = !name.is_empty() &&
+        name != "." &&
+        name != ".." &&
+        !name.contains(['/', '\\']) &&
+        Path::new(name).file_name().is_some_and(|x| x == name);
+    if !valid {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("\"{}\" is not a valid file name", name)));
+    }
+
+    let dir = Path::new(file).parent()
+        .ok_or(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The path has no parent directory"))?;
+    let target = dir.join(name);
+    if target.symlink_metadata().is_ok() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} already exists", target.display())));
+    }
+
+    std::fs::rename(file, &target)?;
+    Ok(target.to_string_lossy().into_owned())
+}
+
+pub fn move_to_trash(file: &str) -> Result<(), std::io::Error> {
+    trash::delete(file).map_err(|err| std::io::Error::other(err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn rename_in_place_rejects_invalid_names() {
+        let dir = temp_dir("rename-invalid");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        std::fs::write(dir.join("b.txt"), "b").unwrap();
+        let file = file.to_str().unwrap();
+
+        for name in ["", ".", "..", "x/y", "x\\y", "/abs"] {
+            let err = rename_in_place(file, name).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+        let err = rename_in_place(file, "b.txt").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(dir.join("b.txt")).unwrap(), "b");
+
+        let renamed = rename_in_place(file, "c.txt").unwrap();
+        assert_eq!(Path::new(&renamed), dir.join("c.txt"));
+        assert!(!Path::new(file).exists());
+    }
+
+    #[test]
+    fn failed_copy_keeps_existing_target() {
+        let dir = temp_dir("copy-existing");
+        std::fs::create_dir_all(dir.join("src/nested")).unwrap();
+        std::fs::write(dir.join("src/nested/a.txt"), "a").unwrap();
+        std::fs::create_dir_all(dir.join("dest")).unwrap();
+        std::fs::write(dir.join("dest/keep.txt"), "keep").unwrap();
+
+        let err = copy_to_target(&dir.join("src"), &dir.join("dest")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(std::fs::read_to_string(dir.join("dest/keep.txt")).unwrap(), "keep");
+
+        copy_to_target(&dir.join("src"), &dir.join("copy")).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("copy/nested/a.txt")).unwrap(), "a");
+    }
+}
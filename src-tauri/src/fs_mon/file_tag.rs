@@ -1,8 +1,10 @@
-use std::{sync::LazyLock, time::SystemTime, str::FromStr};
+use std::{collections::HashMap, sync::{LazyLock, Mutex}, time::SystemTime};
 
 use mac_address::get_mac_address;
 use uuid::{ClockSequence, Timestamp, Uuid};
 
+use super::fs_mon::{get_volume_label, FsMonError};
+
 struct Clock(u128);
 
 impl Clock {
@@ -27,31 +29,325 @@ static CLOCK: LazyLock<Clock> = LazyLock::new(|| -> Clock {
     Clock::new()
 });
 
-pub fn tag_file(file: &str) -> String {
-    let mac_address = get_mac_address().unwrap().unwrap();
+pub trait FileTagger: Send + Sync {
+    fn write_tag(&self, file: &str, tag: &str) -> Result<(), std::io::Error>;
+    fn read_tag(&self, file: &str) -> Option<String>;
+    fn remove_tag(&self, file: &str) -> Result<(), std::io::Error>;
+}
+
+pub struct AdsTagger;
+
+impl FileTagger for AdsTagger {
+    fn write_tag(&self, file: &str, tag: &str) -> Result<(), std::io::Error> {
+        let file = file.to_owned() + ":sb_tag";
+        std::fs::write(file, tag)
+    }
+
+    fn read_tag(&self, file: &str) -> Option<String> {
+        let file = file.to_owned() + ":sb_tag";
+        let res = std::fs::read(file);
+        match res {
+            Ok(res) => {
+                let uuid = String::from_utf8(res).ok()?;
+                Some(uuid)
+            }
+            Err(_) => {
+                None
+            }
+        }
+    }
+
+    fn remove_tag(&self, file: &str) -> Result<(), std::io::Error> {
+        let file = file.to_owned() + ":sb_tag";
+        std::fs::remove_file(file)
+    }
+}
+
+#[cfg(unix)]
+const XATTR_NAME: &str = "user.sb_tag";
+
+#[cfg(unix)]
+pub struct XattrTagger;
+
+#[cfg(unix)]
+impl FileTagger for XattrTagger {
+    fn write_tag(&self, file: &str, tag: &str) -> Result<(), std::io::Error> {
+        xattr::set(file, XATTR_NAME, tag.as_bytes())
+    }
+
+    fn read_tag(&self, file: &str) -> Option<String> {
+        let res = xattr::get(file, XATTR_NAME).ok()??;
+        String::from_utf8(res).ok()
+    }
+
+    fn remove_tag(&self, file: &str) -> Result<(), std::io::Error> {
+        xattr::remove(file, XATTR_NAME)
+    }
+}
+
+pub struct FallbackTagger;
+
+impl FileTagger for FallbackTagger {
+    fn write_tag(&self, _file: &str, _tag: &str) -> Result<(), std::io::Error> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "Volume does not support file tags"))
+    }
+
+    fn read_tag(&self, _file: &str) -> Option<String> {
+        None
+    }
+
+    fn remove_tag(&self, _file: &str) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+pub const MARKER_NAME: &str = ".sb_tag";
+
+pub struct MarkerTagger;
+
+impl FileTagger for MarkerTagger {
+    fn write_tag(&self, file: &str, tag: &str) -> Result<(), std::io::Error> {
+        let marker = std::path::Path::new(file).join(MARKER_NAME);
+        std::fs::write(&marker, tag)?;
+        hide_file(&marker);
+        Ok(())
+    }
+
+    fn read_tag(&self, file: &str) -> Option<String> {
+        let marker = std::path::Path::new(file).join(MARKER_NAME);
+        std::fs::read_to_string(marker).ok()
+    }
+
+    fn remove_tag(&self, file: &str) -> Result<(), std::io::Error> {
+        let marker = std::path::Path::new(file).join(MARKER_NAME);
+        std::fs::remove_file(marker)
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn hide_file(file: &std::path::Path) {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::{SetFileAttributesW, FILE_ATTRIBUTE_HIDDEN};
+
+    let file: Vec<u16> = file.as_os_str().encode_wide().chain(Some(0)).collect();
+    unsafe {
+        SetFileAttributesW(file.as_ptr(), FILE_ATTRIBUTE_HIDDEN);
+    }
+}
+
+#[cfg(unix)]
+fn hide_file(_file: &std::path::Path) {
+}
+
+static ADS_TAGGER: AdsTagger = AdsTagger;
+
+#[cfg(unix)]
+static XATTR_TAGGER: XattrTagger = XattrTagger;
+
+static FALLBACK_TAGGER: FallbackTagger = FallbackTagger;
+
+static MARKER_TAGGER: MarkerTagger = MarkerTagger;
+
+static TAGGERS: LazyLock<Mutex<HashMap<String, &'static dyn FileTagger>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
+
+#[cfg(target_os = "windows")]
+fn select_tagger(volume: &str) -> &'static dyn FileTagger {
+    use std::{ffi::OsStr, os::windows::ffi::OsStrExt};
+    use windows_sys::Win32::{Storage::FileSystem::GetVolumeInformationW, System::SystemServices::FILE_NAMED_STREAMS};
+
+    let volume: Vec<u16> = OsStr::new(volume).encode_wide().chain(Some(0)).collect();
+    let mut flags: u32 = 0;
+    let res = unsafe {
+        GetVolumeInformationW(
+            volume.as_ptr(),
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            &mut flags as *mut u32,
+            std::ptr::null_mut(),
+            0)
+    };
+
+    if res != 0 && flags & FILE_NAMED_STREAMS != 0 {
+        return &ADS_TAGGER;
+    }
+    &FALLBACK_TAGGER
+}
+
+// Whether a filesystem takes user xattrs only shows when writing one, so every
+// volume starts with xattrs and `write_tag` falls back on the first refusal.
+#[cfg(unix)]
+fn select_tagger(_volume: &str) -> &'static dyn FileTagger {
+    &XATTR_TAGGER
+}
+
+fn get_tagger(file: &str) -> Result<&'static dyn FileTagger, FsMonError> {
+    let volume = get_volume_label(file)?;
+    let taggers = &mut *TAGGERS.lock().unwrap();
+    if let Some(tagger) = taggers.get(&volume) {
+        return Ok(*tagger);
+    }
+
+    let tagger = select_tagger(&volume);
+    taggers.insert(volume, tagger);
+    Ok(tagger)
+}
+
+fn set_tagger(file: &str, tagger: &'static dyn FileTagger) {
+    if let Ok(volume) = get_volume_label(file) {
+        TAGGERS.lock().unwrap().insert(volume, tagger);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Fingerprint {
+    pub device: u64,
+    pub inode: u64,
+    pub size: u64,
+    pub modified: u128
+}
+
+fn get_modified(meta: &std::fs::Metadata) -> u128 {
+    let modified = meta.modified()
+        .ok()
+        .and_then(|x| x.duration_since(SystemTime::UNIX_EPOCH).ok());
+
+    match modified {
+        Some(modified) => modified.as_nanos(),
+        None => 0
+    }
+}
+
+fn get_size_and_modified(meta: &std::fs::Metadata) -> (u64, u128) {
+    match meta.is_dir() {
+        true => (0, 0),
+        false => (meta.len(), get_modified(meta))
+    }
+}
+
+#[cfg(unix)]
+pub fn get_fingerprint(file: &str) -> Option<Fingerprint> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = std::fs::metadata(file).ok()?;
+    let (size, modified) = get_size_and_modified(&meta);
+    Some(Fingerprint {
+        device: meta.dev(),
+        inode: meta.ino(),
+        size,
+        modified
+    })
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_fingerprint(file: &str) -> Option<Fingerprint> {
+    use std::os::windows::{fs::OpenOptionsExt, io::AsRawHandle};
+    use windows_sys::Win32::Storage::FileSystem::{
+        GetFileInformationByHandle,
+        BY_HANDLE_FILE_INFORMATION,
+        FILE_FLAG_BACKUP_SEMANTICS
+    };
+
+    let handle = std::fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+        .open(file)
+        .ok()?;
+
+    let meta = handle.metadata().ok()?;
+    let info = unsafe {
+        let mut info: BY_HANDLE_FILE_INFORMATION = std::mem::zeroed();
+        if GetFileInformationByHandle(handle.as_raw_handle(), &mut info as *mut _) == 0 {
+            return None;
+        }
+        info
+    };
+
+    let (size, modified) = get_size_and_modified(&meta);
+    Some(Fingerprint {
+        device: info.dwVolumeSerialNumber as u64,
+        inode: ((info.nFileIndexHigh as u64) << 32) | (info.nFileIndexLow as u64),
+        size,
+        modified
+    })
+}
+
+pub fn new_file_id() -> String {
+    let mac_address = get_mac_address()
+        .ok()
+        .flatten()
+        .map(|x| x.bytes())
+        .unwrap_or_default();
     let clock = &*CLOCK;
-    
-    let uuid = Uuid::new_v1(Timestamp::now(clock), &mac_address.bytes());
-    let uuid = format!("{}", uuid);
 
-    let file = file.to_owned() + ":sb_tag";
-    let file = std::path::PathBuf::from_str(&file).unwrap();
-    std::fs::write(file, &uuid).unwrap();
+    let uuid = Uuid::new_v1(Timestamp::now(clock), &mac_address);
+    format!("{}", uuid)
+}
+
+fn is_dir(file: &str) -> bool {
+    std::fs::metadata(file).is_ok_and(|x| x.is_dir())
+}
 
-    uuid
+pub fn tag_file(file: &str) -> Result<String, std::io::Error> {
+    let uuid = new_file_id();
+    write_tag(file, &uuid)?;
+
+    Ok(uuid)
 }
 
 pub fn get_tag(file: &str) -> Option<String> {
-    let file = file.to_owned() + ":sb_tag";
-    let res = std::fs::read(file);
+    let tag = get_tagger(file).ok()?.read_tag(file);
+    if tag.is_none() && is_dir(file) {
+        return MARKER_TAGGER.read_tag(file);
+    }
+    tag
+}
+
+pub fn remove_tag(file: &str) {
+    if let Ok(tagger) = get_tagger(file) {
+        let _ = tagger.remove_tag(file);
+    }
+    if is_dir(file) {
+        let _ = MARKER_TAGGER.remove_tag(file);
+    }
+}
+
+pub fn write_tag(file: &str, tag: &str) -> Result<(), std::io::Error> {
+    let res = get_tagger(file)?.write_tag(file, tag);
+    if res.as_ref().is_err_and(|x| x.kind() == std::io::ErrorKind::Unsupported) {
+        set_tagger(file, &FALLBACK_TAGGER);
+    }
     match res {
-        Ok(res) => {
-            let uuid = String::from_utf8(res).ok()?;
-            Some(uuid)
-        }
-        Err(_) => {
-            None
-        }
+        Err(_) if is_dir(file) => MARKER_TAGGER.write_tag(file, tag),
+        _ => res
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn xattrs_are_dropped_after_a_refused_write() {
+        let file = std::env::temp_dir().join(format!("side_bin-tag-{}", std::process::id()));
+        std::fs::write(&file, "a").unwrap();
+        let file = file.to_str().unwrap();
+        let tag = tag_file(file).unwrap();
+        assert_eq!(get_tag(file), Some(tag));
+        remove_tag(file);
+        assert_eq!(get_tag(file), None);
+        let _ = std::fs::remove_file(file);
+
+        let unsupported = "/proc/self/status";
+        let err = write_tag(unsupported, "tag").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Unsupported);
+        let err = get_tagger(unsupported).unwrap().write_tag(unsupported, "tag").unwrap_err();
+        assert_eq!(err.to_string(), "Volume does not support file tags");
+    }
+}
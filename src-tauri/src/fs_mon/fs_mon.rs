@@ -1,129 +1,339 @@
-pub enum FileSystemEvent {}
+use std::{collections::VecDeque, ffi::{OsStr, OsString}, path::Path, sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
 
-unsafe extern "C" {
-    fn GetRunningInstance() -> u64;
-    fn Boot(dir: *const u8) -> bool;
-    fn Tick(dir: *const u8) -> bool;
-    fn Peek(dir: *const u8) -> *const FileSystemEvent;
-    fn Pop(dir: *const u8);
-    fn Shutdown(dir: *const u8);
+#[cfg(unix)]
+use std::{collections::HashMap, sync::{LazyLock, Mutex}};
 
-    fn GetLastErr(len: *mut i32) -> *const u8;
-    fn GetAction(event: *const FileSystemEvent) -> i32;
-    fn GetFile(event: *const FileSystemEvent, size: *mut i32) -> *const u8;
+#[cfg(target_os = "windows")]
+use super::win_monitor::{NativeEventIter, SharedWatcher};
+
+#[cfg(target_os = "linux")]
+use super::inotify_monitor::{NativeEventIter, SharedWatcher};
+
+use super::poll_monitor::PollingEventIter;
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+compile_error!("File watching is only implemented for Windows (fs_monitor) and Linux (inotify)");
+
+#[derive(Debug)]
+pub enum FSEvent {
+    FileAdded(OsString),
+    FileRemoved(OsString),
+    FileModified(OsString),
+    FileRenamedOld(OsString),
+    FileRenamedNew(OsString),
+    Overflow(OsString)
 }
 
+impl FSEvent {
+    pub fn path(&self) -> &OsStr {
+        match self {
+            FSEvent::FileAdded(path) |
+            FSEvent::FileRemoved(path) |
+            FSEvent::FileModified(path) |
+            FSEvent::FileRenamedOld(path) |
+            FSEvent::FileRenamedNew(path) |
+            FSEvent::Overflow(path) => path
+        }
+    }
 
-fn get_last_error() -> String {
-    unsafe {
-        let mut l: i32 = 0;
-        let e = GetLastErr(&mut l as *mut i32);
-        let e = &*std::ptr::slice_from_raw_parts(e, l as usize);
-        let err = String::from_utf8_lossy(e);
-        err.to_string()
+    pub fn with_path(self, path: OsString) -> FSEvent {
+        match self {
+            FSEvent::FileAdded(_) => FSEvent::FileAdded(path),
+            FSEvent::FileRemoved(_) => FSEvent::FileRemoved(path),
+            FSEvent::FileModified(_) => FSEvent::FileModified(path),
+            FSEvent::FileRenamedOld(_) => FSEvent::FileRenamedOld(path),
+            FSEvent::FileRenamedNew(_) => FSEvent::FileRenamedNew(path),
+            FSEvent::Overflow(_) => FSEvent::Overflow(path)
+        }
     }
 }
 
-pub fn get_running_instance() -> u64 {
-    let res = unsafe {
-        GetRunningInstance()
-    };
-    res
-}
+const COALESCE_WINDOW: Duration = Duration::from_millis(250);
 
-fn to_null_terminated(s: &str) -> Vec<u8> {
-    let src_bytes = s.as_bytes();
-    let final_size = src_bytes.len() + 1;
-    let mut res = Vec::with_capacity(final_size);
-    res.resize(final_size, 0);
+static NEXT_SEQ: AtomicU64 = AtomicU64::new(1);
 
-    let bytes = &mut res[..final_size - 1];
-    bytes.copy_from_slice(s.as_bytes());
+#[derive(Debug)]
+pub struct TimedEvent {
+    pub seq: u64,
+    pub time: Instant,
+    pub event: FSEvent
+}
 
-    res
+impl TimedEvent {
+    fn new(event: FSEvent) -> Self {
+        TimedEvent {
+            seq: NEXT_SEQ.fetch_add(1, Ordering::Relaxed),
+            time: Instant::now(),
+            event
+        }
+    }
 }
 
+// A full change buffer is not an error: the watch keeps running and reports
+// FSEvent::Overflow so trackers can resync.
 #[derive(Debug)]
-pub enum FSEvent {
-    FileAdded(String),
-    FileRemoved(String),
-    FileModified(String),
-    FileRenamedOld(String),
-    FileRenamedNew(String)
+pub enum FsMonError {
+    BootFailed {
+        root: String,
+        message: String
+    },
+    InvalidPathEncoding {
+        path: OsString
+    },
+    HandleLost {
+        root: String,
+        message: String
+    }
+}
+
+impl std::fmt::Display for FsMonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FsMonError::BootFailed { root, message } => {
+                write!(f, "Can't watch {}: {}", root, message)
+            }
+            FsMonError::InvalidPathEncoding { path } => {
+                write!(f, "Path is not valid Unicode: {}", path.to_string_lossy())
+            }
+            FsMonError::HandleLost { root, message } => {
+                write!(f, "Lost the watch on {}: {}", root, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FsMonError {}
+
+impl From<FsMonError> for std::io::Error {
+    fn from(err: FsMonError) -> Self {
+        let kind = match &err {
+            FsMonError::InvalidPathEncoding { .. } => std::io::ErrorKind::InvalidData,
+            _ => std::io::ErrorKind::Other
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchBackend {
+    Auto,
+    Native,
+    Polling
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchOptions {
+    pub backend: WatchBackend,
+    pub poll_interval: Duration,
+    pub watcher: SharedWatcher
+}
+
+impl Default for WatchOptions {
+    fn default() -> Self {
+        WatchOptions {
+            backend: WatchBackend::Auto,
+            poll_interval: Duration::from_secs(2),
+            watcher: SharedWatcher::default()
+        }
+    }
+}
+
+enum Backend {
+    Native(NativeEventIter),
+    Polling(PollingEventIter)
 }
 
 pub struct FSEventIter {
-    root: String
+    backend: Backend,
+    events: VecDeque<TimedEvent>
 }
 
 impl FSEventIter {
-    pub fn new(root: &str) -> Result<Self, std::io::Error> {
-        let dir = to_null_terminated(&root);
-        let res = unsafe {
-            let dir = dir.as_ptr();
-            Boot(dir)
+    pub fn new(root: &str) -> Result<Self, FsMonError> {
+        Self::with_options(root, &WatchOptions::default())
+    }
+
+    pub fn with_options(root: &str, options: &WatchOptions) -> Result<Self, FsMonError> {
+        let polling = match options.backend {
+            WatchBackend::Auto => is_remote(root),
+            WatchBackend::Native => false,
+            WatchBackend::Polling => true
         };
 
-        if !res {
-            let error = get_last_error();
-            let error = std::io::Error::new(std::io::ErrorKind::Other, error);
-            return Err(error);
-        }
-        let res = FSEventIter {
-            root: root.into()
+        let backend = 'backend: {
+            if !polling {
+                match NativeEventIter::new(root, &options.watcher) {
+                    Ok(iter) => {
+                        break 'backend Backend::Native(iter);
+                    }
+                    Err(err) if options.backend == WatchBackend::Native => {
+                        return Err(err);
+                    }
+                    Err(_) => {}
+                }
+            }
+            Backend::Polling(PollingEventIter::new(root, options.poll_interval)?)
         };
-        Ok(res)
+        Ok(FSEventIter {
+            backend,
+            events: VecDeque::new()
+        })
     }
 
-    pub fn tick(&self) -> Result<(), std::io::Error> {
-        let res = unsafe {
-            let dir = to_null_terminated(&self.root);
-            Tick(dir.as_ptr())
-        };
-        if !res {
-            let error = get_last_error();
-            let error = std::io::Error::new(std::io::ErrorKind::Other, error);
-            return Err(error);
-        }
-        Ok(())
+    pub fn is_polling(&self) -> bool {
+        matches!(self.backend, Backend::Polling(_))
     }
 
-    pub fn get_event(&self) -> Option<FSEvent> {
-        unsafe {
-            let dir = to_null_terminated(&self.root);
-            let evt = Peek(dir.as_ptr());
-            if evt.is_null() {
-                None
+    fn push_event(&mut self, event: FSEvent) {
+        if let FSEvent::FileModified(path) = &event {
+            let previous = self.events.iter()
+                .rev()
+                .find(|x| x.event.path() == path);
+            if previous.is_some_and(|x| matches!(x.event, FSEvent::FileModified(_))) {
+                return;
             }
-            else {
-                let action = GetAction(evt);
-                let mut size: i32 = 0;
-                let file = GetFile(evt, &mut size as *mut i32);
-                let file = &*std::ptr::slice_from_raw_parts(file, size as usize);
-                let file = String::from_utf8(file.into()).unwrap();
-                
-                Pop(dir.as_ptr());
-
-                match action {
-                    1 => Some(FSEvent::FileAdded(file.into())),
-                    2 => Some(FSEvent::FileRemoved(file.into())),
-                    3 => Some(FSEvent::FileModified(file.into())),
-                    4 => Some(FSEvent::FileRenamedOld(file.into())),
-                    5 => Some(FSEvent::FileRenamedNew(file.into())),
-                    _ => None
+        }
+        self.events.push_back(TimedEvent::new(event));
+    }
+
+    pub fn tick(&mut self) -> Result<(), FsMonError> {
+        let res = match &mut self.backend {
+            Backend::Native(iter) => iter.tick(),
+            Backend::Polling(iter) => iter.tick()
+        };
+        loop {
+            let event = match &mut self.backend {
+                Backend::Native(iter) => iter.get_event(),
+                Backend::Polling(iter) => iter.get_event()
+            };
+            match event {
+                Some(event) => {
+                    self.push_event(event);
+                }
+                None => {
+                    break;
                 }
             }
         }
+        res
+    }
+
+    pub fn next_event(&mut self) -> Option<TimedEvent> {
+        let front = self.events.front()?;
+        let ready = match front.event {
+            FSEvent::FileModified(_) => {
+                front.time.elapsed() >= COALESCE_WINDOW ||
+                self.events.iter().any(|x| !matches!(x.event, FSEvent::FileModified(_)))
+            }
+            _ => true
+        };
+        match ready {
+            true => self.events.pop_front(),
+            false => None
+        }
+    }
+
+    pub fn get_event(&mut self) -> Option<FSEvent> {
+        self.next_event().map(|x| x.event)
     }
 }
 
-impl Drop for FSEventIter {
-    fn drop(&mut self) {
-        unsafe {
-            let dir = to_null_terminated(&self.root);
-            Shutdown(dir.as_ptr());
+#[cfg(target_os = "linux")]
+const REMOTE_FS_TYPES: &[u32] = &[
+    0x6969,     // NFS
+    0x517b,     // SMB
+    0xff534d42, // CIFS
+    0xfe534d42, // SMB2
+    0x65735546, // FUSE
+    0x01021997, // 9P
+    0x564c,     // NCP
+    0x73757245  // CODA
+];
+
+#[cfg(target_os = "linux")]
+fn is_remote(root: &str) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let root = match std::ffi::CString::new(std::ffi::OsStr::new(root).as_bytes()) {
+        Ok(root) => root,
+        Err(_) => {
+            return false;
         }
+    };
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    let res = unsafe { libc::statfs(root.as_ptr(), &mut stat) };
+    res == 0 && REMOTE_FS_TYPES.contains(&(stat.f_type as u32))
+}
+
+#[cfg(target_os = "windows")]
+fn is_remote(root: &str) -> bool {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDriveTypeW;
+
+    const DRIVE_REMOTE: u32 = 4;
+
+    let unc = root.starts_with(r"\\") && !root.starts_with(r"\\?\");
+    if unc || root.starts_with(r"\\?\UNC\") {
+        return true;
+    }
+    let volume = match get_volume_label(root) {
+        Ok(volume) => volume,
+        Err(_) => {
+            return false;
+        }
+    };
+    let volume: Vec<u16> = std::ffi::OsStr::new(&volume).encode_wide().chain(Some(0)).collect();
+    unsafe { GetDriveTypeW(volume.as_ptr()) == DRIVE_REMOTE }
+}
+
+fn path_to_string(path: &Path) -> Result<String, FsMonError> {
+    match path.to_str() {
+        Some(path) => Ok(path.into()),
+        None => Err(FsMonError::InvalidPathEncoding {
+            path: path.into()
+        })
     }
 }
 
+#[cfg(target_os = "windows")]
+pub fn get_volume_label(file: impl AsRef<Path>) -> Result<String, FsMonError> {
+    let volume = file.as_ref().ancestors().last().unwrap_or(file.as_ref());
+    path_to_string(volume)
+}
+
+// Mount points rarely change while the app runs, so the mount root found for a
+// device is kept instead of walking the ancestors again on every lookup.
+#[cfg(unix)]
+static VOLUMES: LazyLock<Mutex<HashMap<u64, String>>> = LazyLock::new(|| {
+    Mutex::new(HashMap::new())
+});
 
+#[cfg(unix)]
+pub fn get_volume_label(file: impl AsRef<Path>) -> Result<String, FsMonError> {
+    use std::os::unix::fs::MetadataExt;
+
+    let mut volume = file.as_ref();
+    let device = match volume.metadata() {
+        Ok(meta) => meta.dev(),
+        Err(_) => {
+            return Ok("/".into());
+        }
+    };
+    if let Some(volume) = VOLUMES.lock().unwrap().get(&device) {
+        return Ok(volume.to_owned());
+    }
+
+    while let Some(parent) = volume.parent() {
+        match parent.metadata() {
+            Ok(meta) if meta.dev() == device => {
+                volume = parent;
+            }
+            _ => {
+                break;
+            }
+        }
+    }
+    let volume = path_to_string(volume)?;
+    VOLUMES.lock().unwrap().insert(device, volume.to_owned());
+    Ok(volume)
+}
@@ -0,0 +1,403 @@
+use std::{collections::{HashMap, VecDeque}, ffi::OsString, io::ErrorKind, os::unix::fs::MetadataExt, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use inotify::{EventMask, EventOwned, Inotify, WatchDescriptor, WatchMask};
+
+use super::fs_mon::{FSEvent, FsMonError};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const RENAME_WINDOW: Duration = Duration::from_millis(500);
+const MAX_WATCHES: usize = 8192;
+
+fn watch_mask() -> WatchMask {
+    WatchMask::CREATE |
+    WatchMask::DELETE |
+    WatchMask::CLOSE_WRITE |
+    WatchMask::MOVED_FROM |
+    WatchMask::MOVED_TO |
+    WatchMask::ONLYDIR |
+    WatchMask::DONT_FOLLOW
+}
+
+fn to_os_string(path: &Path) -> OsString {
+    path.as_os_str().to_owned()
+}
+
+#[derive(Debug, Default)]
+struct SharedState {
+    inotify: Option<Inotify>,
+    buffer: Vec<u8>,
+    next_id: u64,
+    owners: HashMap<WatchDescriptor, Vec<u64>>,
+    queues: HashMap<u64, Vec<EventOwned>>
+}
+
+impl SharedState {
+    fn get_inotify(&mut self) -> Result<&mut Inotify, std::io::Error> {
+        let inotify = match self.inotify.take() {
+            Some(inotify) => inotify,
+            None => Inotify::init()?
+        };
+        Ok(self.inotify.insert(inotify))
+    }
+
+    fn add_watch(&mut self, id: u64, path: &Path) -> Result<WatchDescriptor, std::io::Error> {
+        let wd = self.get_inotify()?.watches().add(path, watch_mask())?;
+        let owners = self.owners.entry(wd.to_owned()).or_default();
+        if !owners.contains(&id) {
+            owners.push(id);
+        }
+        Ok(wd)
+    }
+
+    fn remove_watch(&mut self, id: u64, wd: WatchDescriptor) {
+        let owners = match self.owners.get_mut(&wd) {
+            Some(owners) => owners,
+            None => {
+                return;
+            }
+        };
+        owners.retain(|x| *x != id);
+        if owners.is_empty() {
+            self.owners.remove(&wd);
+            if let Some(inotify) = &mut self.inotify {
+                let _ = inotify.watches().remove(wd);
+            }
+        }
+    }
+
+    fn read_events(&mut self) -> Result<(), std::io::Error> {
+        let inotify = match &mut self.inotify {
+            Some(inotify) => inotify,
+            None => {
+                return Ok(());
+            }
+        };
+        if self.buffer.is_empty() {
+            self.buffer = vec![0; BUFFER_SIZE];
+        }
+        loop {
+            let events = match inotify.read_events(&mut self.buffer) {
+                Ok(events) => events,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    break;
+                }
+                Err(err) => {
+                    return Err(err);
+                }
+            };
+            let mut read = false;
+            for event in events {
+                read = true;
+                let event = event.to_owned();
+                let owners: Vec<u64> = match event.mask.contains(EventMask::Q_OVERFLOW) {
+                    true => self.queues.keys().copied().collect(),
+                    false => self.owners.get(&event.wd).cloned().unwrap_or_default()
+                };
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.owners.remove(&event.wd);
+                }
+                for owner in owners {
+                    if let Some(queue) = self.queues.get_mut(&owner) {
+                        queue.push(event.clone());
+                    }
+                }
+            }
+            if !read {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One inotify instance shared by every root watched through it. The kernel
+/// limits instances per user, so a registry keeps a single one for all of its
+/// roots instead of opening one per watched folder.
+#[derive(Debug, Clone, Default)]
+pub struct SharedWatcher {
+    state: Arc<Mutex<SharedState>>
+}
+
+pub struct NativeEventIter {
+    root: PathBuf,
+    device: u64,
+    shared: SharedWatcher,
+    id: u64,
+    watches: HashMap<WatchDescriptor, PathBuf>,
+    moved_from: HashMap<u32, (Instant, PathBuf, bool)>,
+    events: VecDeque<FSEvent>
+}
+
+impl NativeEventIter {
+    pub fn new(root: &str, shared: &SharedWatcher) -> Result<Self, FsMonError> {
+        let boot_failed = |err: std::io::Error| {
+            FsMonError::BootFailed {
+                root: root.into(),
+                message: err.to_string()
+            }
+        };
+        let root = PathBuf::from(root);
+        let device = root.metadata().map_err(boot_failed)?.dev();
+        let id = {
+            let state = &mut *shared.state.lock().unwrap();
+            state.next_id += 1;
+            state.queues.insert(state.next_id, vec![]);
+            state.next_id
+        };
+
+        let mut res = NativeEventIter {
+            root,
+            device,
+            shared: shared.clone(),
+            id,
+            watches: HashMap::new(),
+            moved_from: HashMap::new(),
+            events: VecDeque::new()
+        };
+        res.watch_tree(PathBuf::new()).map_err(boot_failed)?;
+        Ok(res)
+    }
+
+    // Folders are watched breadth first, so when a root holds more than
+    // `MAX_WATCHES` folders the ones closest to the root are still covered.
+    fn watch_tree(&mut self, dir: PathBuf) -> Result<(), std::io::Error> {
+        let mut pending = VecDeque::from([dir]);
+        while let Some(dir) = pending.pop_front() {
+            if self.watches.len() >= MAX_WATCHES {
+                break;
+            }
+            let full_path = self.root.join(&dir);
+            let wd = self.shared.state.lock().unwrap().add_watch(self.id, &full_path);
+            let wd = match wd {
+                Ok(wd) => wd,
+                Err(err) => {
+                    if dir.as_os_str().is_empty() {
+                        return Err(err);
+                    }
+                    continue;
+                }
+            };
+            self.watches.insert(wd, dir.to_owned());
+
+            let entries = match std::fs::read_dir(&full_path) {
+                Ok(entries) => entries,
+                Err(_) => {
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let meta = match entry.metadata() {
+                    Ok(meta) => meta,
+                    Err(_) => {
+                        continue;
+                    }
+                };
+                if meta.is_dir() && meta.dev() == self.device {
+                    pending.push_back(dir.join(entry.file_name()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn unwatch_tree(&mut self, dir: &Path) {
+        let wds: Vec<WatchDescriptor> = self.watches.iter()
+            .filter(|(_, path)| path.starts_with(dir))
+            .map(|(wd, _)| wd.to_owned())
+            .collect();
+
+        let state = &mut *self.shared.state.lock().unwrap();
+        for wd in wds {
+            self.watches.remove(&wd);
+            state.remove_watch(self.id, wd);
+        }
+    }
+
+    fn move_tree(&mut self, old: &Path, new: &Path) {
+        for path in self.watches.values_mut() {
+            if let Ok(relative) = path.strip_prefix(old) {
+                *path = new.join(relative);
+            }
+        }
+    }
+
+    fn read_events(&mut self) -> Result<Vec<EventOwned>, std::io::Error> {
+        let state = &mut *self.shared.state.lock().unwrap();
+        state.read_events()?;
+        Ok(state.queues.get_mut(&self.id).map(std::mem::take).unwrap_or_default())
+    }
+
+    fn handle_lost(&self, message: String) -> FsMonError {
+        FsMonError::HandleLost {
+            root: self.root.to_string_lossy().into_owned(),
+            message
+        }
+    }
+
+    pub fn tick(&mut self) -> Result<(), FsMonError> {
+        let events = self.read_events().map_err(|err| self.handle_lost(err.to_string()))?;
+
+        let mut pending: Vec<FSEvent> = vec![];
+        let mut error = None;
+        let mut overflowed = false;
+
+        for event in events {
+            if event.mask.contains(EventMask::Q_OVERFLOW) {
+                overflowed = true;
+                pending.push(FSEvent::Overflow(OsString::new()));
+                continue;
+            }
+            if event.mask.contains(EventMask::IGNORED) {
+                let removed = self.watches.remove(&event.wd);
+                if removed.is_some_and(|dir| dir.as_os_str().is_empty()) {
+                    error = Some(self.handle_lost("The watched directory was removed".into()));
+                }
+                continue;
+            }
+
+            let dir = match self.watches.get(&event.wd) {
+                Some(dir) => dir.to_owned(),
+                None => {
+                    continue;
+                }
+            };
+            let path = match &event.name {
+                Some(name) => dir.join(name),
+                None => {
+                    continue;
+                }
+            };
+            let is_dir = event.mask.contains(EventMask::ISDIR);
+
+            if event.mask.contains(EventMask::CREATE) {
+                if is_dir {
+                    let _ = self.watch_tree(path.to_owned());
+                }
+                pending.push(FSEvent::FileAdded(to_os_string(&path)));
+            }
+            else if event.mask.contains(EventMask::DELETE) {
+                pending.push(FSEvent::FileRemoved(to_os_string(&path)));
+            }
+            else if event.mask.contains(EventMask::CLOSE_WRITE) {
+                pending.push(FSEvent::FileModified(to_os_string(&path)));
+            }
+            else if event.mask.contains(EventMask::MOVED_FROM) {
+                self.moved_from.insert(event.cookie, (Instant::now(), path.to_owned(), is_dir));
+                pending.push(FSEvent::FileRenamedOld(to_os_string(&path)));
+            }
+            else if event.mask.contains(EventMask::MOVED_TO) {
+                match self.moved_from.remove(&event.cookie) {
+                    Some((_, old_path, _)) => {
+                        if is_dir {
+                            self.move_tree(&old_path, &path);
+                        }
+                        pending.push(FSEvent::FileRenamedNew(to_os_string(&path)));
+                    }
+                    None => {
+                        if is_dir {
+                            let _ = self.watch_tree(path.to_owned());
+                        }
+                        pending.push(FSEvent::FileAdded(to_os_string(&path)));
+                    }
+                }
+            }
+        }
+
+        let expired: Vec<u32> = self.moved_from.iter()
+            .filter(|(_, (time, _, _))| time.elapsed() >= RENAME_WINDOW)
+            .map(|(cookie, _)| *cookie)
+            .collect();
+        for cookie in expired {
+            if let Some((_, path, is_dir)) = self.moved_from.remove(&cookie) {
+                if is_dir {
+                    self.unwatch_tree(&path);
+                }
+                pending.push(FSEvent::FileRemoved(to_os_string(&path)));
+            }
+        }
+
+        if overflowed {
+            let _ = self.watch_tree(PathBuf::new());
+        }
+
+        self.events.extend(pending);
+        match error {
+            Some(error) => Err(error),
+            None => Ok(())
+        }
+    }
+
+    pub fn get_event(&mut self) -> Option<FSEvent> {
+        self.events.pop_front()
+    }
+}
+
+impl Drop for NativeEventIter {
+    fn drop(&mut self) {
+        let state = &mut *self.shared.state.lock().unwrap();
+        for wd in self.watches.drain().map(|(wd, _)| wd) {
+            state.remove_watch(self.id, wd);
+        }
+        state.queues.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn drain(iter: &mut NativeEventIter) -> Vec<FSEvent> {
+        let mut events = vec![];
+        while let Some(event) = iter.get_event() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[test]
+    fn unpaired_move_expires_in_a_later_tick() {
+        let dir = temp_dir("inotify");
+        std::fs::create_dir_all(dir.join("watched")).unwrap();
+        std::fs::write(dir.join("watched/a.txt"), "a").unwrap();
+
+        let mut iter = NativeEventIter::new(dir.join("watched").to_str().unwrap(), &SharedWatcher::default()).unwrap();
+        std::fs::rename(dir.join("watched/a.txt"), dir.join("watched/b.txt")).unwrap();
+        std::fs::rename(dir.join("watched/b.txt"), dir.join("c.txt")).unwrap();
+
+        iter.tick().unwrap();
+        let events = drain(&mut iter);
+        assert!(matches!(&events[..], [
+            FSEvent::FileRenamedOld(a),
+            FSEvent::FileRenamedNew(b),
+            FSEvent::FileRenamedOld(c)
+        ] if a == "a.txt" && b == "b.txt" && c == "b.txt"));
+
+        std::thread::sleep(RENAME_WINDOW);
+        iter.tick().unwrap();
+        let events = drain(&mut iter);
+        assert!(matches!(&events[..], [FSEvent::FileRemoved(b)] if b == "b.txt"));
+    }
+
+    #[test]
+    fn roots_share_one_instance() {
+        let dir = temp_dir("inotify-shared");
+        std::fs::create_dir_all(dir.join("a/b")).unwrap();
+
+        let shared = SharedWatcher::default();
+        let mut outer = NativeEventIter::new(dir.join("a").to_str().unwrap(), &shared).unwrap();
+        let mut inner = NativeEventIter::new(dir.join("a/b").to_str().unwrap(), &shared).unwrap();
+        std::fs::write(dir.join("a/b/c.txt"), "c").unwrap();
+
+        inner.tick().unwrap();
+        outer.tick().unwrap();
+        assert!(matches!(&drain(&mut inner)[..], [FSEvent::FileAdded(x), ..] if x == "c.txt"));
+        assert!(matches!(&drain(&mut outer)[..], [FSEvent::FileAdded(x), ..] if x == "b/c.txt"));
+
+        drop(inner);
+        std::fs::write(dir.join("a/b/d.txt"), "d").unwrap();
+        outer.tick().unwrap();
+        assert!(matches!(&drain(&mut outer)[..], [FSEvent::FileAdded(x), ..] if x == "b/d.txt"));
+        assert_eq!(shared.state.lock().unwrap().queues.len(), 1);
+    }
+}
@@ -0,0 +1,360 @@
+use std::{collections::{HashMap, HashSet, VecDeque}, ffi::OsString, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, Ordering}, mpsc::{sync_channel, Receiver, SyncSender, TryRecvError}, Arc}, time::{Duration, Instant, SystemTime}};
+
+use super::fs_mon::{FSEvent, FsMonError};
+
+const SNAPSHOT_LIMIT: usize = 200_000;
+const STOP_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+const POLL_BACKLOG: usize = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct EntryInfo {
+    identity: Option<(u64, u64)>,
+    modified: Option<SystemTime>,
+    size: u64,
+    is_dir: bool
+}
+
+struct Snapshot {
+    entries: HashMap<PathBuf, EntryInfo>,
+    complete: bool
+}
+
+#[cfg(unix)]
+fn get_identity(_path: &Path, meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(target_os = "windows")]
+fn get_identity(path: &Path, _meta: &std::fs::Metadata) -> Option<(u64, u64)> {
+    let fingerprint = super::file_tag::get_fingerprint(&path.to_string_lossy())?;
+    Some((fingerprint.device, fingerprint.inode))
+}
+
+fn take_snapshot(root: &Path, stop: &AtomicBool) -> Option<Snapshot> {
+    let mut snapshot = Snapshot {
+        entries: HashMap::new(),
+        complete: true
+    };
+
+    let mut pending = vec![PathBuf::new()];
+    while let Some(dir) = pending.pop() {
+        if stop.load(Ordering::Relaxed) {
+            return None;
+        }
+        let entries = match std::fs::read_dir(root.join(&dir)) {
+            Ok(entries) => entries,
+            Err(_) if dir.as_os_str().is_empty() => {
+                return None;
+            }
+            Err(_) => {
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            if snapshot.entries.len() >= SNAPSHOT_LIMIT {
+                snapshot.complete = false;
+                return Some(snapshot);
+            }
+            let meta = match entry.path().symlink_metadata() {
+                Ok(meta) => meta,
+                Err(_) => {
+                    continue;
+                }
+            };
+
+            let path = dir.join(entry.file_name());
+            let info = EntryInfo {
+                identity: get_identity(&entry.path(), &meta),
+                modified: meta.modified().ok(),
+                size: meta.len(),
+                is_dir: meta.is_dir()
+            };
+            if info.is_dir {
+                pending.push(path.to_owned());
+            }
+            snapshot.entries.insert(path, info);
+        }
+    }
+    Some(snapshot)
+}
+
+fn has_parent_in(path: &Path, paths: &HashSet<&PathBuf>) -> bool {
+    path.parent().is_some_and(|parent| paths.contains(&parent.to_path_buf()))
+}
+
+fn diff_snapshots(old: &Snapshot, new: &Snapshot) -> Vec<FSEvent> {
+    let mut removed: Vec<&PathBuf> = old.entries.iter()
+        .filter(|(path, info)| {
+            match new.entries.get(*path) {
+                Some(new_info) => new_info.identity != info.identity,
+                None => new.complete
+            }
+        })
+        .map(|(path, _)| path)
+        .collect();
+    let mut added: Vec<&PathBuf> = new.entries.iter()
+        .filter(|(path, info)| {
+            match old.entries.get(*path) {
+                Some(old_info) => old_info.identity != info.identity,
+                None => old.complete
+            }
+        })
+        .map(|(path, _)| path)
+        .collect();
+    let mut modified: Vec<&PathBuf> = new.entries.iter()
+        .filter(|(path, info)| {
+            match old.entries.get(*path) {
+                Some(old_info) => {
+                    !info.is_dir &&
+                    old_info.identity == info.identity &&
+                    (old_info.modified != info.modified || old_info.size != info.size)
+                }
+                None => false
+            }
+        })
+        .map(|(path, _)| path)
+        .collect();
+    removed.sort();
+    added.sort();
+    modified.sort();
+
+    let mut added_by_identity: HashMap<(u64, u64), &PathBuf> = HashMap::new();
+    for path in added.iter() {
+        if let Some(identity) = new.entries[*path].identity {
+            added_by_identity.insert(identity, path);
+        }
+    }
+
+    let mut renames: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+    for path in removed.iter() {
+        let renamed = old.entries[*path].identity
+            .and_then(|identity| added_by_identity.remove(&identity));
+        if let Some(new_path) = renamed {
+            renames.insert(path, new_path);
+        }
+    }
+
+    let gone: HashSet<&PathBuf> = removed.iter().copied().collect();
+    let appeared: HashSet<&PathBuf> = added.iter().copied().collect();
+    let renamed_to: HashSet<&PathBuf> = renames.values().copied().collect();
+
+    let mut events = vec![];
+    for path in removed.iter() {
+        match renames.get(path) {
+            Some(new_path) => {
+                let parent_renamed = match (path.parent(), new_path.parent()) {
+                    (Some(old_parent), Some(new_parent)) => {
+                        renames.get(&old_parent.to_path_buf()).is_some_and(|x| x.as_path() == new_parent) &&
+                        path.file_name() == new_path.file_name()
+                    }
+                    _ => false
+                };
+                if !parent_renamed {
+                    events.push(FSEvent::FileRenamedOld(path.as_os_str().to_owned()));
+                    events.push(FSEvent::FileRenamedNew(new_path.as_os_str().to_owned()));
+                }
+            }
+            None => {
+                if !has_parent_in(path, &gone) {
+                    events.push(FSEvent::FileRemoved(path.as_os_str().to_owned()));
+                }
+            }
+        }
+    }
+    for path in added.iter() {
+        if !renamed_to.contains(path) && !has_parent_in(path, &appeared) {
+            events.push(FSEvent::FileAdded(path.as_os_str().to_owned()));
+        }
+    }
+    for path in modified {
+        events.push(FSEvent::FileModified(path.as_os_str().to_owned()));
+    }
+    events
+}
+
+fn wait(interval: Duration, stop: &AtomicBool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < interval {
+        if stop.load(Ordering::Relaxed) {
+            return false;
+        }
+        std::thread::sleep(STOP_CHECK_INTERVAL.min(interval.saturating_sub(start.elapsed())));
+    }
+    !stop.load(Ordering::Relaxed)
+}
+
+fn poll(root: PathBuf, interval: Duration, stop: Arc<AtomicBool>, sender: SyncSender<Vec<FSEvent>>) {
+    let mut snapshot = loop {
+        if let Some(snapshot) = take_snapshot(&root, &stop) {
+            break snapshot;
+        }
+        if !wait(interval, &stop) {
+            return;
+        }
+    };
+
+    while wait(interval, &stop) {
+        let new_snapshot = match take_snapshot(&root, &stop) {
+            Some(new_snapshot) => new_snapshot,
+            None => {
+                continue;
+            }
+        };
+
+        let mut events = diff_snapshots(&snapshot, &new_snapshot);
+        if snapshot.complete && !new_snapshot.complete {
+            events.push(FSEvent::Overflow(OsString::new()));
+        }
+        snapshot = new_snapshot;
+        if !events.is_empty() && sender.send(events).is_err() {
+            return;
+        }
+    }
+}
+
+pub struct PollingEventIter {
+    root: String,
+    events: VecDeque<FSEvent>,
+    receiver: Receiver<Vec<FSEvent>>,
+    stop: Arc<AtomicBool>
+}
+
+impl PollingEventIter {
+    pub fn new(root: &str, interval: Duration) -> Result<Self, FsMonError> {
+        let boot_failed = |message: String| {
+            FsMonError::BootFailed {
+                root: root.into(),
+                message
+            }
+        };
+        let meta = std::fs::metadata(root).map_err(|err| boot_failed(err.to_string()))?;
+        if !meta.is_dir() {
+            return Err(boot_failed("Not a directory".into()));
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = sync_channel(POLL_BACKLOG);
+        {
+            let root = PathBuf::from(root);
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                poll(root, interval, stop, sender);
+            });
+        }
+
+        Ok(PollingEventIter {
+            root: root.into(),
+            events: VecDeque::new(),
+            receiver,
+            stop
+        })
+    }
+
+    pub fn tick(&mut self) -> Result<(), FsMonError> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(events) => {
+                    self.events.extend(events);
+                }
+                Err(TryRecvError::Empty) => {
+                    return Ok(());
+                }
+                Err(TryRecvError::Disconnected) => {
+                    return Err(FsMonError::HandleLost {
+                        root: self.root.to_owned(),
+                        message: "The polling thread stopped".into()
+                    });
+                }
+            }
+        }
+    }
+
+    pub fn get_event(&mut self) -> Option<FSEvent> {
+        self.events.pop_front()
+    }
+}
+
+impl Drop for PollingEventIter {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(entries: &[(&str, u64, u64)], complete: bool) -> Snapshot {
+        let entries = entries.iter()
+            .map(|(path, inode, size)| {
+                let info = EntryInfo {
+                    identity: Some((1, *inode)),
+                    modified: None,
+                    size: *size,
+                    is_dir: !path.contains('.')
+                };
+                (PathBuf::from(path), info)
+            })
+            .collect();
+        Snapshot { entries, complete }
+    }
+
+    fn describe(events: Vec<FSEvent>) -> Vec<String> {
+        events.iter().map(|x| format!("{:?}", x)).collect()
+    }
+
+    #[test]
+    fn diff_reports_each_change_once() {
+        let old = snapshot(&[
+            ("a.txt", 1, 0),
+            ("b.txt", 2, 0),
+            ("c.txt", 3, 0),
+            ("dir", 4, 0),
+            ("dir/d.txt", 5, 0),
+            ("gone", 6, 0),
+            ("gone/e.txt", 7, 0)
+        ], true);
+        let new = snapshot(&[
+            ("a.txt", 1, 10),
+            ("c2.txt", 3, 0),
+            ("moved", 4, 0),
+            ("moved/d.txt", 5, 0),
+            ("fresh", 8, 0),
+            ("fresh/f.txt", 9, 0)
+        ], true);
+
+        assert_eq!(describe(diff_snapshots(&old, &new)), [
+            "FileRemoved(\"b.txt\")",
+            "FileRenamedOld(\"c.txt\")",
+            "FileRenamedNew(\"c2.txt\")",
+            "FileRenamedOld(\"dir\")",
+            "FileRenamedNew(\"moved\")",
+            "FileRemoved(\"gone\")",
+            "FileAdded(\"fresh\")",
+            "FileModified(\"a.txt\")"
+        ]);
+    }
+
+    #[test]
+    fn renamed_parent_still_reports_moved_children() {
+        let old = snapshot(&[("dir", 1, 0), ("dir/a.txt", 2, 0)], true);
+        let new = snapshot(&[("other", 1, 0), ("a.txt", 2, 0)], true);
+
+        assert_eq!(describe(diff_snapshots(&old, &new)), [
+            "FileRenamedOld(\"dir\")",
+            "FileRenamedNew(\"other\")",
+            "FileRenamedOld(\"dir/a.txt\")",
+            "FileRenamedNew(\"a.txt\")"
+        ]);
+    }
+
+    #[test]
+    fn incomplete_snapshots_add_and_remove_nothing() {
+        let old = snapshot(&[("a.txt", 1, 0), ("b.txt", 2, 0)], false);
+        let new = snapshot(&[("a.txt", 1, 5), ("c.txt", 3, 0)], false);
+
+        assert_eq!(describe(diff_snapshots(&old, &new)), ["FileModified(\"a.txt\")"]);
+    }
+}
@@ -0,0 +1,121 @@
+use std::{collections::VecDeque, path::Path, sync::atomic::{AtomicBool, Ordering}};
+
+use super::file_tag::MARKER_NAME;
+
+pub const DEFAULT_SEARCH_LIMIT: usize = 20_000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SearchResult {
+    Found(String),
+    NotFound,
+    Truncated
+}
+
+pub fn find_file<F>(root: &Path, skip: Option<&Path>, limit: usize, matches: F) -> SearchResult
+where F: Fn(&str) -> bool {
+    find_file_cancellable(root, skip, limit, &AtomicBool::new(false), matches)
+}
+
+pub fn find_file_cancellable<F>(
+    root: &Path,
+    skip: Option<&Path>,
+    limit: usize,
+    cancel: &AtomicBool,
+    matches: F) -> SearchResult
+where F: Fn(&str) -> bool {
+    let mut visited = 0;
+    let mut pending = VecDeque::from([root.to_path_buf()]);
+
+    while let Some(dir) = pending.pop_front() {
+        if cancel.load(Ordering::Relaxed) {
+            return SearchResult::NotFound;
+        }
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            visited += 1;
+            if visited > limit {
+                return SearchResult::Truncated;
+            }
+
+            let path = entry.path();
+            if skip.is_some_and(|skip| path.starts_with(skip)) {
+                continue;
+            }
+
+            let path_str = match path.to_str() {
+                Some(path_str) => path_str,
+                None => {
+                    continue;
+                }
+            };
+            if matches(path_str) {
+                return SearchResult::Found(path_str.into());
+            }
+
+            let is_dir = entry.file_type().is_ok_and(|x| x.is_dir());
+            if is_dir {
+                pending.push_back(path);
+            }
+        }
+    }
+    SearchResult::NotFound
+}
+
+pub const DEFAULT_STATS_LIMIT: usize = 200_000;
+
+#[derive(Debug, Clone, Default)]
+pub struct DirStats {
+    pub items: u64,
+    pub size: u64,
+    pub complete: bool
+}
+
+pub fn get_dir_stats(root: &Path, limit: usize) -> Option<DirStats> {
+    if !root.is_dir() {
+        return None;
+    }
+
+    let mut stats = DirStats::default();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        for entry in entries.flatten() {
+            if stats.items as usize >= limit {
+                return Some(stats);
+            }
+            if entry.file_name() == MARKER_NAME {
+                continue;
+            }
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(_) => {
+                    continue;
+                }
+            };
+
+            stats.items += 1;
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            }
+            else if let Ok(meta) = entry.metadata() {
+                stats.size += meta.len();
+            }
+        }
+    }
+
+    stats.complete = true;
+    Some(stats)
+}
@@ -1,27 +1,308 @@
-use std::{collections::HashMap, convert::Infallible, str::FromStr, sync::{Arc, LazyLock, Mutex, Weak}};
+use std::{cell::{OnceCell, RefCell}, collections::{HashMap, VecDeque}, convert::Infallible, ffi::{OsStr, OsString}, mem::Discriminant, path::{Path, PathBuf}, str::FromStr, sync::{atomic::{AtomicBool, Ordering}, mpsc::{sync_channel, Receiver, SyncSender}, Arc, Condvar, Mutex, Weak}, time::{Duration, Instant, SystemTime}};
 
-use super::{file_tag::{get_tag, tag_file}, fs_mon::{FSEvent, FSEventIter}};
+use super::{content_hash::{get_content_hash, ContentHash}, file_ops::{copy_to_dir, move_to_dir, move_to_trash, rename_in_place, MoveError}, file_tag::{get_fingerprint, get_tag, new_file_id, remove_tag, tag_file, write_tag, Fingerprint}, fs_mon::{get_volume_label, FSEvent, FSEventIter, FsMonError, TimedEvent, WatchOptions}, search::{find_file, find_file_cancellable, SearchResult, DEFAULT_SEARCH_LIMIT}};
 
 type FileTrackersMap = HashMap<String, FileTracker>;
 type DirTrackersMap = HashMap<String, Weak<Mutex<DirTracker>>>;
 
-static FILE_TRACKERS: LazyLock<Mutex<FileTrackersMap>> = LazyLock::new(|| -> Mutex<FileTrackersMap> {
-    Mutex::new(FileTrackersMap::new())
-});
-
-static DIR_TRACKERS: LazyLock<Mutex<DirTrackersMap>> = LazyLock::new(|| -> Mutex<DirTrackersMap> {
-    Mutex::new(DirTrackersMap::new())
-});
+const DIR_TRACKER_RETRY: Duration = Duration::from_secs(1);
+const DIR_TRACKER_RESTARTS: usize = 5;
+const EVENT_WINDOW: Duration = Duration::from_secs(2);
+const DELETED_RECONCILE: Duration = Duration::from_secs(60 * 60);
+const SUBSCRIBER_CAPACITY: usize = 256;
 
 struct DirTracker {
     root: String,
-    iter: FSEventIter,
-    events: Vec<FSEvent>
+    iter: Option<FSEventIter>,
+    retry_at: Instant,
+    failures: usize,
+    abandoned: bool,
+    reported: Vec<Discriminant<FsMonError>>,
+    events: VecDeque<TimedEvent>,
+    fresh: usize,
+    overflowed: bool
+}
+
+fn report(reported: &mut Vec<Discriminant<FsMonError>>, err: FsMonError, errors: &mut Vec<FsMonError>) {
+    let kind = std::mem::discriminant(&err);
+    if !reported.contains(&kind) {
+        reported.push(kind);
+        errors.push(err);
+    }
+}
+
+impl DirTracker {
+    fn read_events(&mut self, watch_options: &WatchOptions, errors: &mut Vec<FsMonError>) {
+        self.fresh = self.events.len();
+        let iter = match &mut self.iter {
+            Some(iter) => iter,
+            None => {
+                if self.abandoned || Instant::now() < self.retry_at {
+                    return;
+                }
+                self.retry_at = Instant::now() + DIR_TRACKER_RETRY;
+                match FSEventIter::with_options(&self.root, watch_options) {
+                    Ok(iter) => {
+                        self.failures = 0;
+                        self.reported.clear();
+                        self.overflowed = true;
+                        self.iter.insert(iter)
+                    }
+                    Err(err) => {
+                        self.failures += 1;
+                        self.abandoned = self.failures >= DIR_TRACKER_RESTARTS;
+                        report(&mut self.reported, err, errors);
+                        return;
+                    }
+                }
+            }
+        };
+
+        let res = iter.tick();
+        while let Some(timed) = iter.next_event() {
+            if let FSEvent::Overflow(_) = timed.event {
+                self.overflowed = true;
+            }
+            let path = get_full_path(&self.root, timed.event.path());
+            if path.to_str().is_none() {
+                report(&mut self.reported, FsMonError::InvalidPathEncoding { path }, errors);
+                continue;
+            }
+            self.events.push_back(TimedEvent {
+                event: timed.event.with_path(path),
+                ..timed
+            });
+        }
+        if let Err(err) = res {
+            self.retry_at = Instant::now() + DIR_TRACKER_RETRY;
+            self.iter = None;
+            report(&mut self.reported, err, errors);
+        }
+    }
+
+    fn fresh_events(&self) -> impl Iterator<Item = &TimedEvent> {
+        self.events.range(self.fresh..)
+    }
+
+    fn expire_events(&mut self) {
+        while self.events.front().is_some_and(|x| x.time.elapsed() > EVENT_WINDOW) {
+            self.events.pop_front();
+        }
+    }
+
+    fn get_renamed_old(&self, seq: u64) -> Option<&str> {
+        self.events.iter()
+            .filter(|x| x.seq < seq)
+            .filter_map(|x| {
+                match &x.event {
+                    FSEvent::FileRenamedOld(path) => Some((x.seq, path.to_str()?)),
+                    _ => None
+                }
+            })
+            .max_by_key(|(seq, _)| *seq)
+            .map(|(_, path)| path)
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+enum FileIdentity {
+    Tag,
+    Fingerprint(Fingerprint)
+}
+
+impl FileIdentity {
+    fn matches(&self, id: &str, file: &str) -> bool {
+        match self {
+            FileIdentity::Tag => {
+                get_tag(file).is_some_and(|tag| tag.eq(id))
+            }
+            FileIdentity::Fingerprint(fingerprint) => {
+                get_fingerprint(file).is_some_and(|x| x.eq(fingerprint))
+            }
+        }
+    }
+}
+
+struct SearchJob {
+    root: String,
+    skip: Option<String>,
+    wanted: Vec<(String, FileIdentity)>
+}
+
+fn search_for(job: &SearchJob, limit: usize) -> Vec<(String, String)> {
+    let found = RefCell::new(vec![]);
+    find_file(
+        Path::new(&job.root),
+        job.skip.as_deref().map(Path::new),
+        limit,
+        |path| {
+            let found = &mut *found.borrow_mut();
+            let tag = OnceCell::new();
+            let fingerprint = OnceCell::new();
+            for (id, identity) in job.wanted.iter() {
+                if found.iter().any(|(x, _)| x == id) {
+                    continue;
+                }
+                let matches = match identity {
+                    FileIdentity::Tag => {
+                        tag.get_or_init(|| get_tag(path)).as_ref() == Some(id)
+                    }
+                    FileIdentity::Fingerprint(expected) => {
+                        fingerprint.get_or_init(|| get_fingerprint(path)).as_ref() == Some(expected)
+                    }
+                };
+                if matches {
+                    found.push((id.to_owned(), path.to_owned()));
+                }
+            }
+            found.len() == job.wanted.len()
+        });
+    found.into_inner()
 }
 
 struct FileTracker {
     dir_tracker: Arc<Mutex<DirTracker>>,
-    tracker_state: FileTrackerState
+    identity: FileIdentity,
+    content_hash: Option<ContentHash>,
+    unconfirmed: bool,
+    tracker_state: FileTrackerState,
+    state_since: Instant,
+    last_path: String,
+    registered_at: u64,
+    recovering: bool,
+    searching: bool,
+    searched_at: Instant,
+    widened: usize,
+    search_root: Option<String>,
+    shelf: String
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PersistedEntry {
+    id: String,
+    path: String,
+    registered_at: u64,
+    identity: FileIdentity,
+    content_hash: Option<ContentHash>,
+    #[serde(default)]
+    unconfirmed: bool,
+    #[serde(default)]
+    deleted: bool
+}
+
+struct PendingRegistration {
+    file: String,
+    file_id: String,
+    identity: FileIdentity,
+    content_hash: Option<ContentHash>,
+    root: String
+}
+
+impl PendingRegistration {
+    fn new(file: &str, root: String, options: &RegisterOptions) -> Result<Self, std::io::Error> {
+        let (file_id, identity) = match tag_file(file) {
+            Ok(file_id) => (file_id, FileIdentity::Tag),
+            Err(_) => {
+                let fingerprint = get_fingerprint(file)
+                    .ok_or(std::io::Error::from(std::io::ErrorKind::NotFound))?;
+                (new_file_id(), FileIdentity::Fingerprint(fingerprint))
+            }
+        };
+        let content_hash = match options.content_hash {
+            true => get_content_hash(file),
+            false => None
+        };
+
+        Ok(PendingRegistration {
+            file: file.into(),
+            file_id,
+            identity,
+            content_hash,
+            root
+        })
+    }
+
+    fn rollback(&self) {
+        if matches!(self.identity, FileIdentity::Tag) {
+            remove_tag(&self.file);
+        }
+    }
+
+    fn into_tracker(self, dir_tracker: Arc<Mutex<DirTracker>>, shelf: &str) -> FileTracker {
+        FileTracker {
+            dir_tracker,
+            identity: self.identity,
+            content_hash: self.content_hash,
+            unconfirmed: false,
+            tracker_state: FileTrackerState::Certain {
+                id: self.file_id,
+                path: self.file.to_owned()
+            },
+            state_since: Instant::now(),
+            last_path: self.file,
+            registered_at: unix_millis(),
+            recovering: false,
+            searching: false,
+            searched_at: Instant::now(),
+            widened: 0,
+            search_root: None,
+            shelf: shelf.into()
+        }
+    }
+}
+
+pub const DEFAULT_SHELF: &str = "default";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchScope {
+    Parent,
+    Ancestors(usize),
+    Volume
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterOptions {
+    pub content_hash: bool,
+    pub watch_scope: WatchScope
+}
+
+impl Default for RegisterOptions {
+    fn default() -> Self {
+        RegisterOptions {
+            content_hash: false,
+            watch_scope: WatchScope::Volume
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SearchOptions {
+    pub widen_levels: usize,
+    pub search_limit: usize
+}
+
+impl Default for SearchOptions {
+    fn default() -> Self {
+        SearchOptions {
+            widen_levels: 3,
+            search_limit: DEFAULT_SEARCH_LIMIT
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct GracePeriods {
+    pub lost: Duration,
+    pub deleted: Duration
+}
+
+impl Default for GracePeriods {
+    fn default() -> Self {
+        GracePeriods {
+            lost: Duration::from_secs(1),
+            deleted: Duration::from_secs(30)
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -40,7 +321,7 @@ fn get_relative_path(base: &str, path: &str) -> Result<String, Error> {
     match relative {
         Ok(relative) => {
             let relative = relative.to_str().ok_or(Error)?;
-            return Ok(relative.into());
+            Ok(relative.into())
         }
         Err(_) => {
             Err(Error)
@@ -48,31 +329,291 @@ fn get_relative_path(base: &str, path: &str) -> Result<String, Error> {
     }
 }
 
-fn check_potential_path(base: &str, partial_path: &str) ->
-    Result<(String, String), Error> {
-    let full_path: String = 'full: {
-        if partial_path.is_empty() {
-            break 'full base.into();
+fn get_full_path(root: &str, name: &OsStr) -> OsString {
+    if name.is_empty() {
+        return root.into();
+    }
+    std::path::Path::new(root).join(name).into_os_string()
+}
+
+fn get_full_path_checked(base: &str, partial_path: &str) -> Result<String, Error> {
+    if partial_path.is_empty() {
+        return Ok(base.into());
+    }
+    let partial_path = std::path::PathBuf::from_str(partial_path)?;
+    let base = std::path::PathBuf::from_str(base)?;
+
+    let full_path = base.join(partial_path);
+    let full_path = full_path.to_str().ok_or(Error)?;
+    Ok(full_path.into())
+}
+
+fn check_potential_path(base: &str, partial_path: &str, id: &str, identity: &FileIdentity) ->
+    Result<String, Error> {
+    let full_path = get_full_path_checked(base, partial_path)?;
+    match identity.matches(id, &full_path) {
+        true => Ok(full_path),
+        false => Err(Error)
+    }
+}
+
+fn read_shelf_file(path: &Path) -> Result<Vec<PersistedEntry>, std::io::Error> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(vec![]);
+        }
+        Err(err) => {
+            return Err(std::io::Error::new(err.kind(), format!("{}: {}", path.display(), err)));
         }
-        let partial_path = std::path::PathBuf::from_str(partial_path)?;
-        let base = std::path::PathBuf::from_str(base)?;
+    };
+    serde_json::from_str(&content)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{}: {}", path.display(), err)))
+}
+
+fn write_shelf_file(path: &Path, entries: &[PersistedEntry]) -> Result<(), std::io::Error> {
+    let content = serde_json::to_string_pretty(entries)?;
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let temp = path.with_extension("json.tmp");
+    std::fs::write(&temp, content)?;
+    std::fs::rename(&temp, path)
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+// Tracker paths are Unicode and so is every ancestor of one, so the volume
+// label can't fail on them.
+fn get_volume(file: &str) -> String {
+    get_volume_label(file).unwrap_or_else(|_| file.to_owned())
+}
+
+fn get_existing_ancestor(file: &str) -> String {
+    let ancestor = Path::new(file).ancestors()
+        .skip(1)
+        .find(|x| x.exists());
+
+    match ancestor {
+        Some(ancestor) => ancestor.to_string_lossy().into_owned(),
+        None => get_volume(file)
+    }
+}
 
-        let full_path = base.join(partial_path);
-        let full_path = full_path.to_str().ok_or(Error)?;
-        full_path.into()
+fn get_scope_root(file: &str, watch_scope: WatchScope) -> String {
+    let volume = get_volume(file);
+    let levels = match watch_scope {
+        WatchScope::Parent => 1,
+        WatchScope::Ancestors(levels) => std::cmp::max(levels, 1),
+        // inotify needs a watch on every directory below the root, so a whole
+        // filesystem is never watched on Linux.
+        WatchScope::Volume if cfg!(target_os = "linux") => 1,
+        WatchScope::Volume => {
+            return volume;
+        }
     };
-    let tag = get_tag(&full_path).ok_or(Error)?;
-    Ok((tag, full_path.into()))
+
+    let mut root = Path::new(file);
+    for _ in 0..levels {
+        if root == Path::new(&volume) {
+            break;
+        }
+        root = match root.parent() {
+            Some(parent) => parent,
+            None => {
+                break;
+            }
+        };
+    }
+
+    match root.starts_with(&volume) {
+        true => root.to_string_lossy().into_owned(),
+        false => volume
+    }
+}
+
+type RegisterResult = Result<(String, bool), std::io::Error>;
+
+// Registered entries are given as (id, identity, shelf)
+fn find_registered<'a>(registered: &[(&str, &FileIdentity, &'a str)], file: &str) -> Option<&'a str> {
+    let tag = get_tag(file);
+    let fingerprint = get_fingerprint(file);
+    registered.iter()
+        .find(|(id, identity, _)| {
+            tag.as_deref() == Some(*id) ||
+                matches!(identity, FileIdentity::Fingerprint(x) if fingerprint.as_ref() == Some(x))
+        })
+        .map(|(_, _, shelf)| *shelf)
+}
+
+fn accept_files<'a>(
+    files: &'a [String],
+    shelf: &str,
+    registered: &[(&str, &FileIdentity, &str)],
+    max_slots: Option<usize>) -> (Vec<RegisterResult>, Vec<(usize, &'a str)>) {
+    let mut free_slots = max_slots.map(|max_slots| {
+        max_slots.saturating_sub(registered.iter().filter(|(_, _, x)| *x == shelf).count())
+    });
+    let mut seen: Vec<&str> = vec![];
+    let mut results = vec![];
+    let mut accepted = vec![];
+    for (index, file) in files.iter().enumerate() {
+        if seen.contains(&file.as_str()) {
+            results.push(Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                "File is already part of this batch")));
+            continue;
+        }
+        seen.push(file);
+
+        if let Some(shelf) = find_registered(registered, file) {
+            results.push(Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("File is already on shelf \"{}\"", shelf))));
+            continue;
+        }
+        if std::fs::symlink_metadata(file).is_err() {
+            results.push(Err(std::io::Error::from(std::io::ErrorKind::NotFound)));
+            continue;
+        }
+        if free_slots == Some(0) {
+            results.push(Err(std::io::Error::new(
+                std::io::ErrorKind::QuotaExceeded,
+                format!("Shelf \"{}\" has no free slots", shelf))));
+            continue;
+        }
+        free_slots = free_slots.map(|x| x - 1);
+        results.push(Ok((String::new(), false)));
+        accepted.push((index, file.as_str()));
+    }
+    (results, accepted)
+}
+
+fn abort_batch(results: &mut [RegisterResult], files: &[String], accepted: &[usize], failed: usize, err: std::io::Error) {
+    for index in accepted.iter() {
+        results[*index] = Err(match *index == failed {
+            true => std::io::Error::new(err.kind(), err.to_string()),
+            false => std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                format!("Not registered because {} failed: {}", files[failed], err))
+        });
+    }
+}
+
+fn prepare_registrations(accepted: &[(usize, &str, String)], options: &RegisterOptions) ->
+    Result<Vec<PendingRegistration>, (usize, std::io::Error)> {
+    let mut pending: Vec<PendingRegistration> = vec![];
+    for (index, file, root) in accepted.iter() {
+        match PendingRegistration::new(file, root.to_owned(), options) {
+            Ok(registration) => {
+                pending.push(registration);
+            }
+            Err(err) => {
+                for registration in pending.iter() {
+                    registration.rollback();
+                }
+                return Err((*index, err));
+            }
+        }
+    }
+    Ok(pending)
+}
+
+fn get_dir_tracker(dir_trackers: &mut DirTrackersMap, root: &str, watch_options: &WatchOptions) ->
+    Result<Arc<Mutex<DirTracker>>, std::io::Error> {
+    let existing = dir_trackers.iter()
+        .filter(|(tracker_root, _)| Path::new(root).starts_with(tracker_root))
+        .filter_map(|(tracker_root, tracker)| {
+            tracker.upgrade().map(|x| (tracker_root.len(), x))
+        })
+        .max_by_key(|(len, _)| *len);
+
+    if let Some((_, tracker)) = existing {
+        return Ok(tracker);
+    }
+
+    let fs_event_iter = FSEventIter::with_options(root, watch_options)?;
+    Ok(insert_dir_tracker(dir_trackers, root, Some(fs_event_iter)))
+}
+
+fn insert_dir_tracker(dir_trackers: &mut DirTrackersMap, root: &str, iter: Option<FSEventIter>) ->
+    Arc<Mutex<DirTracker>> {
+    let dir_tracker = Arc::new(
+        Mutex::new(
+            DirTracker {
+                root: root.to_owned(),
+                iter,
+                retry_at: Instant::now(),
+                failures: 0,
+                abandoned: false,
+                reported: vec![],
+                events: VecDeque::new(),
+                fresh: 0,
+                overflowed: false
+            }));
+
+    let weak_tracker = Arc::downgrade(&dir_tracker);
+    dir_trackers.insert(root.to_owned(), weak_tracker);
+
+    dir_tracker
 }
 
 impl FileTracker {
+    fn refresh_identity(&mut self, id: &str, path: &str) -> bool {
+        if self.identity.matches(id, path) {
+            return true;
+        }
+        self.identity = match write_tag(path, id) {
+            Ok(()) => FileIdentity::Tag,
+            Err(_) => {
+                match get_fingerprint(path) {
+                    Some(fingerprint) => FileIdentity::Fingerprint(fingerprint),
+                    None => {
+                        return false;
+                    }
+                }
+            }
+        };
+        true
+    }
+
     pub fn update_state(&mut self) {
         let dir_tracker = &*self.dir_tracker.lock().unwrap();
-        for event in dir_tracker.events.iter() {
+        if dir_tracker.abandoned {
+            match &self.tracker_state {
+                FileTrackerState::Certain { id, .. } |
+                FileTrackerState::Renaming { id, .. } |
+                FileTrackerState::Moving { id, .. } => {
+                    self.tracker_state = FileTrackerState::Lost {
+                        id: id.to_owned(),
+                        partial_path: String::new(),
+                        since: unix_millis()
+                    };
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        for timed in dir_tracker.fresh_events() {
+            let event = &timed.event;
+            // Paths that aren't valid Unicode can't be matched against the tracked
+            // paths, which are kept as `String`s, so their events are skipped.
+            let event_path = match event.path().to_str() {
+                Some(event_path) => event_path,
+                None => {
+                    continue;
+                }
+            };
             match event {
-                FSEvent::FileRenamedOld(old_path) => {
+                FSEvent::FileRenamedOld(_) => {
                     if let FileTrackerState::Certain { id, path } = &self.tracker_state {
-                        let res = get_relative_path(old_path, path);
+                        let res = get_relative_path(event_path, path);
                         if let Ok(relative) = res {
                             self.tracker_state = FileTrackerState::Renaming {
                                 id: id.to_owned(),
@@ -81,23 +622,34 @@ impl FileTracker {
                         }
                     }
                 }
-                FSEvent::FileRenamedNew(path) => {
-                    if let FileTrackerState::Renaming { id, partial_path } = &self.tracker_state {
-                        let tag = check_potential_path(path, partial_path);
-                        if let Ok((tag, path)) = tag {
-                            if id.eq(&tag) {
-                                self.tracker_state =
-                                    FileTrackerState::Certain { 
-                                        id: id.to_owned(),
-                                        path
-                                    };
-                            }
+                FSEvent::FileRenamedNew(_) => {
+                    if let FileTrackerState::Certain { id, path } = &self.tracker_state {
+                        let path = dir_tracker.get_renamed_old(timed.seq)
+                            .and_then(|old_path| get_relative_path(old_path, path).ok())
+                            .and_then(|relative| check_potential_path(event_path, &relative, id, &self.identity).ok());
+                        if let Some(path) = path {
+                            self.tracker_state =
+                                FileTrackerState::Certain {
+                                    id: id.to_owned(),
+                                    path
+                                };
+                        }
+                    }
+                    else if let FileTrackerState::Renaming { id, partial_path } |
+                        FileTrackerState::Lost { id, partial_path, .. } = &self.tracker_state {
+                        let path = check_potential_path(event_path, partial_path, id, &self.identity);
+                        if let Ok(path) = path {
+                            self.tracker_state =
+                                FileTrackerState::Certain {
+                                    id: id.to_owned(),
+                                    path
+                                };
                         }
                     }
                 }
-                FSEvent::FileRemoved(old_path) => {
+                FSEvent::FileRemoved(_) => {
                     if let FileTrackerState::Certain { id, path } = &self.tracker_state {
-                        let res = get_relative_path(old_path, path);
+                        let res = get_relative_path(event_path, path);
                         if let Ok(relative) = res {
                             self.tracker_state = FileTrackerState::Moving {
                                 id: id.to_owned(),
@@ -105,28 +657,229 @@ impl FileTracker {
                             };
                         }
                     }
+                    else if let FileTrackerState::Renaming { id, partial_path } = &self.tracker_state {
+                        if dir_tracker.get_renamed_old(timed.seq) == Some(event_path) {
+                            self.tracker_state = FileTrackerState::Moving {
+                                id: id.to_owned(),
+                                partial_path: partial_path.to_owned()
+                            };
+                        }
+                    }
+                }
+                FSEvent::FileAdded(_) => {
+                    if let FileTrackerState::Moving { id, partial_path } |
+                        FileTrackerState::Lost { id, partial_path, .. } = &self.tracker_state {
+                        let path = check_potential_path(event_path, partial_path, id, &self.identity);
+                        if let Ok(path) = path {
+                            self.tracker_state =
+                                FileTrackerState::Certain {
+                                    id: id.to_owned(),
+                                    path
+                                };
+                        }
+                    }
                 }
-                FSEvent::FileAdded(path) => {
-                    if let FileTrackerState::Moving { id, partial_path } = &self.tracker_state {
-                        let tag = check_potential_path(path, partial_path);
-                        if let Ok((tag, path)) = tag {
-                            if id.eq(&tag) {
-                                self.tracker_state =
-                                    FileTrackerState::Certain { 
-                                        id: id.to_owned(),
-                                        path
-                                    };
+                FSEvent::FileModified(_) => {
+                    if let FileTrackerState::Certain { path, .. } = &self.tracker_state {
+                        if let FileIdentity::Fingerprint(fingerprint) = &mut self.identity {
+                            if event_path == path {
+                                if let Some(new_fingerprint) = get_fingerprint(path) {
+                                    *fingerprint = new_fingerprint;
+                                }
                             }
                         }
                     }
                 }
-                _ => {}
+                FSEvent::Overflow(_) => {}
+            }
+        }
+    }
+
+    fn reconcile(&mut self, candidates: &[(String, Arc<Mutex<DirTracker>>)]) {
+        let (id, partial_path) = match &self.tracker_state {
+            FileTrackerState::Certain { .. } => {
+                return;
+            }
+            FileTrackerState::Deleted { id } => {
+                if self.state_since.elapsed() >= DELETED_RECONCILE {
+                    return;
+                }
+                (id.to_owned(), String::new())
+            }
+            FileTrackerState::Renaming { id, partial_path } |
+            FileTrackerState::Moving { id, partial_path } |
+            FileTrackerState::Lost { id, partial_path, .. } => (id.to_owned(), partial_path.to_owned())
+        };
+
+        let mut content_matches: Vec<(String, &Arc<Mutex<DirTracker>>)> = vec![];
+        for (candidate, dir_tracker) in candidates.iter() {
+            if let Ok(path) = check_potential_path(candidate, &partial_path, &id, &self.identity) {
+                if !self.refresh_identity(&id, &path) {
+                    continue;
+                }
+                self.dir_tracker = dir_tracker.clone();
+                self.tracker_state = FileTrackerState::Certain {
+                    id,
+                    path
+                };
+                return;
+            }
+
+            let content_hash = match &self.content_hash {
+                Some(content_hash) => content_hash,
+                None => {
+                    continue;
+                }
+            };
+            let paths = get_full_path_checked(candidate, &partial_path).into_iter()
+                .chain([candidate.to_owned()]);
+            for path in paths {
+                if content_matches.iter().any(|(x, _)| *x == path) || get_tag(&path).is_some() {
+                    continue;
+                }
+                if content_hash.matches(&path) {
+                    content_matches.push((path, dir_tracker));
+                }
+            }
+        }
+
+        if let [(path, dir_tracker)] = &content_matches[..] {
+            let fingerprint = match get_fingerprint(path) {
+                Some(fingerprint) => fingerprint,
+                None => {
+                    return;
+                }
+            };
+            self.identity = FileIdentity::Fingerprint(fingerprint);
+            self.unconfirmed = true;
+            self.dir_tracker = (*dir_tracker).clone();
+            self.tracker_state = FileTrackerState::Certain {
+                id,
+                path: path.to_owned()
+            };
+        }
+    }
+
+    fn resync(&mut self) -> Option<(String, FileIdentity)> {
+        let id = match &self.tracker_state {
+            FileTrackerState::Certain { id, path } => {
+                if let FileIdentity::Fingerprint(fingerprint) = &mut self.identity {
+                    let new_fingerprint = get_fingerprint(path)
+                        .filter(|x| x.device == fingerprint.device && x.inode == fingerprint.inode);
+                    if let Some(new_fingerprint) = new_fingerprint {
+                        *fingerprint = new_fingerprint;
+                    }
+                }
+                if self.identity.matches(id, path) {
+                    return None;
+                }
+                id.to_owned()
             }
+            FileTrackerState::Renaming { id, .. } |
+            FileTrackerState::Moving { id, .. } |
+            FileTrackerState::Lost { id, .. } => {
+                if self.identity.matches(id, &self.last_path) {
+                    self.tracker_state = FileTrackerState::Certain {
+                        id: id.to_owned(),
+                        path: self.last_path.to_owned()
+                    };
+                    return None;
+                }
+                id.to_owned()
+            }
+            FileTrackerState::Deleted { .. } => {
+                return None;
+            }
+        };
+
+        if let FileTrackerState::Certain { .. } = self.tracker_state {
+            self.tracker_state = FileTrackerState::Moving {
+                id: id.to_owned(),
+                partial_path: String::new()
+            };
+        }
+        if self.searching {
+            return None;
+        }
+        self.searching = true;
+        Some((id, self.identity.to_owned()))
+    }
+
+    fn check_grace_periods(&mut self, grace_periods: &GracePeriods) {
+        let elapsed = self.state_since.elapsed();
+        let state = match &self.tracker_state {
+            FileTrackerState::Renaming { id, partial_path } |
+            FileTrackerState::Moving { id, partial_path } => {
+                if elapsed < grace_periods.lost {
+                    return;
+                }
+                FileTrackerState::Lost {
+                    id: id.to_owned(),
+                    partial_path: partial_path.to_owned(),
+                    since: unix_millis()
+                }
+            }
+            FileTrackerState::Lost { id, .. } => {
+                if self.recovering || self.searching || elapsed < grace_periods.deleted {
+                    return;
+                }
+                match self.identity.matches(id, &self.last_path) {
+                    true => FileTrackerState::Certain {
+                        id: id.to_owned(),
+                        path: self.last_path.to_owned()
+                    },
+                    false => FileTrackerState::Deleted {
+                        id: id.to_owned()
+                    }
+                }
+            }
+            _ => {
+                return;
+            }
+        };
+
+        self.tracker_state = state;
+        self.state_since = Instant::now();
+    }
+
+    fn widen_scope(&mut self, grace_periods: &GracePeriods, search_options: &SearchOptions) -> Option<SearchJob> {
+        let id = match &self.tracker_state {
+            FileTrackerState::Lost { id, .. } => id.to_owned(),
+            _ => {
+                return None;
+            }
+        };
+        if self.searching || self.widened >= search_options.widen_levels {
+            return None;
         }
+        if self.widened > 0 && self.searched_at.elapsed() < grace_periods.lost {
+            return None;
+        }
+
+        let root = match &self.search_root {
+            Some(root) => root.to_owned(),
+            None => self.dir_tracker.lock().unwrap().root.to_owned()
+        };
+        let volume = get_volume(&root);
+        let parent = match Path::new(&root).parent() {
+            Some(parent) if root != volume && parent != Path::new(&volume) => parent.to_string_lossy().into_owned(),
+            _ => {
+                return None;
+            }
+        };
+
+        self.searching = true;
+        self.widened += 1;
+        self.search_root = Some(parent.to_owned());
+        Some(SearchJob {
+            root: parent,
+            skip: Some(root),
+            wanted: vec![(id, self.identity.to_owned())]
+        })
     }
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
 pub enum FileTrackerState {
     Certain {
         id: String,
@@ -139,128 +892,1455 @@ pub enum FileTrackerState {
     Moving {
         id: String,
         partial_path: String
+    },
+    Lost {
+        id: String,
+        partial_path: String,
+        since: u64
+    },
+    Deleted {
+        id: String
     }
 }
 
-fn get_volume_label(file: &str) -> String {
-    let mut file = Some(std::path::PathBuf::from_str(file).unwrap()).unwrap();
-    while let Some(parent) = file.parent() {
-        file = parent.to_path_buf();
-    }
-    file.to_str().unwrap().into()
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct StateChange {
+    pub id: String,
+    pub old: FileTrackerState,
+    pub new: FileTrackerState,
+    pub unconfirmed: bool
+}
+
+pub struct TickResult {
+    pub changes: Vec<StateChange>,
+    pub errors: Vec<FsMonError>
 }
 
-pub fn unregister_file(file: &str) {
-    let trackers = &mut *(*FILE_TRACKERS).lock().unwrap();
-    trackers.remove(file);
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RecoveryProgress {
+    pub id: String,
+    pub root: String,
+    pub searched: usize,
+    pub total: usize,
+    pub truncated: Vec<String>
 }
 
-pub fn register_file(file: &str) -> String {
-    let trackers = &mut *(*FILE_TRACKERS).lock().unwrap();
-    let file_id = tag_file(file);
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct Entry {
+    pub id: String,
+    pub shelf: String,
+    pub path: String,
+    pub weak: bool,
+    pub unconfirmed: bool,
+    pub state: FileTrackerState
+}
 
-    let state = FileTrackerState::Certain {
-        id: file_id.to_owned(),
-        path: file.into()
-    };
+pub struct TrackerRegistry {
+    file_trackers: Mutex<FileTrackersMap>,
+    dir_trackers: Mutex<DirTrackersMap>,
+    persistence: Option<PathBuf>,
+    unsaved_shelves: Mutex<Vec<String>>,
+    subscribers: Mutex<Vec<SyncSender<StateChange>>>,
+    watch_options: Mutex<WatchOptions>,
+    search_options: Mutex<SearchOptions>,
+    grace_periods: Mutex<GracePeriods>,
+    searches: Mutex<Vec<SearchJob>>,
+    searches_ready: Condvar,
+    registering: Mutex<()>,
+    slot_limits: Mutex<HashMap<String, usize>>
+}
 
-    let volume = get_volume_label(file);
+impl Default for TrackerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    let dir_tracker = 'retrieve_tracker: {
-        let dir_trackers = &mut *DIR_TRACKERS.lock().unwrap();
-        let tracker = dir_trackers.get(&volume);
+impl TrackerRegistry {
+    pub fn new() -> Self {
+        TrackerRegistry {
+            file_trackers: Mutex::new(FileTrackersMap::new()),
+            dir_trackers: Mutex::new(DirTrackersMap::new()),
+            persistence: None,
+            unsaved_shelves: Mutex::new(vec![]),
+            subscribers: Mutex::new(vec![]),
+            watch_options: Mutex::new(WatchOptions::default()),
+            search_options: Mutex::new(SearchOptions::default()),
+            grace_periods: Mutex::new(GracePeriods::default()),
+            searches: Mutex::new(vec![]),
+            searches_ready: Condvar::new(),
+            registering: Mutex::new(()),
+            slot_limits: Mutex::new(HashMap::new())
+        }
+    }
 
-        if let Some(tracker) = tracker {
-            let real_tracker = tracker.upgrade();
-            if let Some(tracker) = real_tracker {
-                break 'retrieve_tracker tracker;
-            }
+    pub fn with_persistence(path: PathBuf) -> Self {
+        TrackerRegistry {
+            persistence: Some(path),
+            ..Self::new()
         }
+    }
 
-        let fs_event_iter = FSEventIter::new(&volume).unwrap();
-        let dir_tracker = Arc::new(
-            Mutex::new(
-                DirTracker {
-                    root: volume.to_owned(),
-                    iter: fs_event_iter,
-                    events: vec![]
-                }));
+    pub fn set_watch_options(&self, watch_options: WatchOptions) {
+        let current = &mut *self.watch_options.lock().unwrap();
+        *current = WatchOptions {
+            watcher: current.watcher.clone(),
+            ..watch_options
+        };
+    }
 
-        let weak_tracker = Arc::downgrade(&dir_tracker);
-        dir_trackers.insert(volume, weak_tracker);
+    fn get_watch_options(&self) -> WatchOptions {
+        self.watch_options.lock().unwrap().clone()
+    }
 
-        dir_tracker
-    };
+    pub fn set_search_options(&self, search_options: SearchOptions) {
+        *self.search_options.lock().unwrap() = search_options;
+    }
 
-    let tracker = FileTracker {
-        dir_tracker,
-        tracker_state: state
-    };
+    fn get_search_options(&self) -> SearchOptions {
+        *self.search_options.lock().unwrap()
+    }
 
-    trackers.insert(file_id.to_owned(), tracker);
-    file_id
-}
-
-pub fn get_tracker_state(id: &str) -> Option<FileTrackerState> {
-    let trackers = &mut *(*FILE_TRACKERS).lock().unwrap();
-    let state = trackers.get(id);
-    state.map(|x| {
-        x.tracker_state.to_owned()
-    })
-}
-
-pub fn tick() {
-    let mut ids = vec![];
-    let dir_trackers = &mut *DIR_TRACKERS.lock().unwrap();
-    for (id, tracker) in dir_trackers.iter() {
-        let tracker = tracker.upgrade();
-        if let Some(tracker) = tracker {
-            let tracker = &mut *tracker.lock().unwrap();
-            tracker.events.clear();
-            tracker.iter.tick().unwrap();
-            while let Some(event) = tracker.iter.get_event() {
-                let event = match event {
-                    FSEvent::FileRenamedOld(name) => {
-                        let mut full_path = tracker.root.to_owned();
-                        full_path.push_str(&name);
-                        FSEvent::FileRenamedOld(full_path.into())
-                    }
-                    FSEvent::FileRenamedNew(name) => {
-                        let mut full_path = tracker.root.to_owned();
-                        full_path.push_str(&name);
-                        FSEvent::FileRenamedNew(full_path.into())
-                    }
-                    FSEvent::FileAdded(name) => {
-                        let mut full_path = tracker.root.to_owned();
-                        full_path.push_str(&name);
-                        FSEvent::FileAdded(full_path.into())
-                    }
-                    FSEvent::FileRemoved(name) => {
-                        let mut full_path = tracker.root.to_owned();
-                        full_path.push_str(&name);
-                        FSEvent::FileRemoved(full_path.into())
-                    }
-                    FSEvent::FileModified(name) => {
-                        let mut full_path = tracker.root.to_owned();
-                        full_path.push_str(&name);
-                        FSEvent::FileModified(full_path.into())
-                    }
-                };
-                tracker.events.push(event);
-            }
-        }
-        else {
-            ids.push(id.to_owned());
-        }
+    pub fn set_grace_periods(&self, grace_periods: GracePeriods) {
+        *self.grace_periods.lock().unwrap() = grace_periods;
     }
 
-    for id in ids.iter() {
-        dir_trackers.remove(id);
+    fn get_grace_periods(&self) -> GracePeriods {
+        *self.grace_periods.lock().unwrap()
     }
 
-    let file_trackers = &mut *FILE_TRACKERS.lock().unwrap();
-    for (_, tracker) in file_trackers.iter_mut() {
-        tracker.update_state();
+    pub fn set_slot_limits(&self, slot_limits: HashMap<String, usize>) {
+        *self.slot_limits.lock().unwrap() = slot_limits;
+    }
+
+    fn get_slot_limit(&self, shelf: &str) -> Option<usize> {
+        self.slot_limits.lock().unwrap().get(shelf).copied()
+    }
+
+    pub fn subscribe(&self) -> Receiver<StateChange> {
+        let (sender, receiver) = sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
     }
-}
 
+    fn notify(&self, changes: &[StateChange]) {
+        let subscribers = &mut *self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| {
+            changes.iter().all(|change| subscriber.try_send(change.to_owned()).is_ok())
+        });
+    }
+
+    fn get_shelf_file(&self, shelf: &str) -> Option<PathBuf> {
+        let path = self.persistence.as_ref()?;
+        if shelf == DEFAULT_SHELF {
+            return Some(path.to_owned());
+        }
+        let stem = path.file_stem()?.to_string_lossy();
+        Some(path.with_file_name(format!("{}.{}.json", stem, shelf)))
+    }
+
+    fn save(&self, file_trackers: &FileTrackersMap, shelf: &str) {
+        let path = match self.get_shelf_file(shelf) {
+            Some(path) => path,
+            None => {
+                return;
+            }
+        };
+        if self.unsaved_shelves.lock().unwrap().iter().any(|x| x == shelf) {
+            return;
+        }
+
+        let entries: Vec<PersistedEntry> = file_trackers.iter()
+            .filter(|(_, tracker)| tracker.shelf == shelf)
+            .map(|(id, tracker)| {
+                PersistedEntry {
+                    id: id.to_owned(),
+                    path: tracker.last_path.to_owned(),
+                    registered_at: tracker.registered_at,
+                    identity: tracker.identity.to_owned(),
+                    content_hash: tracker.content_hash.to_owned(),
+                    unconfirmed: tracker.unconfirmed,
+                    deleted: matches!(tracker.tracker_state, FileTrackerState::Deleted { .. })
+                }
+            })
+            .collect();
+
+        let _ = write_shelf_file(&path, &entries);
+    }
+
+    fn save_shelves(&self, file_trackers: &FileTrackersMap, changes: &[StateChange]) {
+        let mut shelves: Vec<&str> = vec![];
+        for change in changes.iter() {
+            if let Some(tracker) = file_trackers.get(&change.id) {
+                if !shelves.contains(&tracker.shelf.as_str()) {
+                    shelves.push(&tracker.shelf);
+                }
+            }
+        }
+        for shelf in shelves {
+            self.save(file_trackers, shelf);
+        }
+    }
+
+    pub fn restore(&self, shelves: &[String], options: &RegisterOptions) -> Vec<std::io::Error> {
+        shelves.iter()
+            .filter_map(|shelf| self.restore_shelf(shelf, options).err())
+            .collect()
+    }
+
+    fn refuse_saving(&self, shelf: &str) -> bool {
+        let unsaved_shelves = &mut *self.unsaved_shelves.lock().unwrap();
+        if unsaved_shelves.iter().any(|x| x == shelf) {
+            return false;
+        }
+        unsaved_shelves.push(shelf.into());
+        true
+    }
+
+    fn restore_shelf(&self, shelf: &str, options: &RegisterOptions) -> Result<(), std::io::Error> {
+        let watch_options = self.get_watch_options();
+        let path = match self.get_shelf_file(shelf) {
+            Some(path) => path,
+            None => {
+                return Ok(());
+            }
+        };
+        let entries = match read_shelf_file(&path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::InvalidData => {
+                let mut message = format!("{}, not saving this shelf", err);
+                if self.refuse_saving(shelf) {
+                    let backup = path.with_extension(format!("json.{}.bak", unix_millis()));
+                    if std::fs::copy(&path, &backup).is_ok() {
+                        message = format!("{} (backed up to {})", message, backup.display());
+                    }
+                }
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, message));
+            }
+            Err(err) => {
+                self.refuse_saving(shelf);
+                return Err(err);
+            }
+        };
+        self.unsaved_shelves.lock().unwrap().retain(|x| x != shelf);
+
+        let mut watch_error = None;
+        let dir_trackers = &mut *self.dir_trackers.lock().unwrap();
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        for entry in entries {
+            if file_trackers.contains_key(&entry.id) {
+                continue;
+            }
+
+            let verified = !entry.deleted && entry.identity.matches(&entry.id, &entry.path);
+            let (root, state) = match verified {
+                true => {
+                    let root = get_scope_root(&entry.path, options.watch_scope);
+                    let state = FileTrackerState::Certain {
+                        id: entry.id.to_owned(),
+                        path: entry.path.to_owned()
+                    };
+                    (root, state)
+                }
+                false => {
+                    let ancestor = get_existing_ancestor(&entry.path);
+                    let root = get_scope_root(&ancestor, options.watch_scope);
+                    let state = match entry.deleted {
+                        true => {
+                            FileTrackerState::Deleted {
+                                id: entry.id.to_owned()
+                            }
+                        }
+                        false => {
+                            FileTrackerState::Lost {
+                                id: entry.id.to_owned(),
+                                partial_path: String::new(),
+                                since: unix_millis()
+                            }
+                        }
+                    };
+                    (root, state)
+                }
+            };
+
+            // Dropping the entry would lose it on the next save, so it waits
+            // for the tick to start its watcher instead.
+            let dir_tracker = match get_dir_tracker(dir_trackers, &root, &watch_options) {
+                Ok(dir_tracker) => dir_tracker,
+                Err(err) => {
+                    watch_error.get_or_insert(err);
+                    insert_dir_tracker(dir_trackers, &root, None)
+                }
+            };
+
+            let tracker = FileTracker {
+                dir_tracker,
+                identity: entry.identity,
+                content_hash: entry.content_hash,
+                unconfirmed: entry.unconfirmed,
+                tracker_state: state,
+                state_since: Instant::now(),
+                last_path: entry.path,
+                registered_at: entry.registered_at,
+                recovering: false,
+                searching: false,
+                searched_at: Instant::now(),
+                widened: 0,
+                search_root: None,
+                shelf: shelf.into()
+            };
+            file_trackers.insert(entry.id, tracker);
+        }
+        match watch_error {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    pub fn read_saved(&self, shelves: &[String]) -> (Vec<Entry>, Vec<std::io::Error>) {
+        let mut entries = vec![];
+        let mut errors = vec![];
+        for shelf in shelves.iter() {
+            let saved = match self.get_shelf_file(shelf).map(|path| read_shelf_file(&path)) {
+                Some(Ok(saved)) => saved,
+                Some(Err(err)) => {
+                    errors.push(err);
+                    continue;
+                }
+                None => {
+                    continue;
+                }
+            };
+            for saved_entry in saved {
+                let state = match (saved_entry.deleted, saved_entry.identity.matches(&saved_entry.id, &saved_entry.path)) {
+                    (true, _) => FileTrackerState::Deleted {
+                        id: saved_entry.id.to_owned()
+                    },
+                    (false, true) => FileTrackerState::Certain {
+                        id: saved_entry.id.to_owned(),
+                        path: saved_entry.path.to_owned()
+                    },
+                    (false, false) => FileTrackerState::Lost {
+                        id: saved_entry.id.to_owned(),
+                        partial_path: String::new(),
+                        since: unix_millis()
+                    }
+                };
+                let entry = Entry {
+                    weak: matches!(saved_entry.identity, FileIdentity::Fingerprint(_)),
+                    id: saved_entry.id,
+                    shelf: shelf.to_owned(),
+                    path: saved_entry.path,
+                    unconfirmed: saved_entry.unconfirmed,
+                    state
+                };
+                entries.push((saved_entry.registered_at, entry));
+            }
+        }
+
+        entries.sort_by(|(a_time, a), (b_time, b)| {
+            a_time.cmp(b_time).then_with(|| a.path.cmp(&b.path))
+        });
+        (entries.into_iter().map(|(_, entry)| entry).collect(), errors)
+    }
+
+    pub fn list(&self, shelf: Option<&str>) -> Vec<Entry> {
+        let trackers = &*self.file_trackers.lock().unwrap();
+        let mut entries: Vec<(u64, Entry)> = trackers.iter()
+            .filter(|(_, tracker)| shelf.is_none_or(|shelf| tracker.shelf == shelf))
+            .map(|(id, tracker)| {
+                let entry = Entry {
+                    id: id.to_owned(),
+                    shelf: tracker.shelf.to_owned(),
+                    path: tracker.last_path.to_owned(),
+                    weak: matches!(tracker.identity, FileIdentity::Fingerprint(_)),
+                    unconfirmed: tracker.unconfirmed,
+                    state: tracker.tracker_state.to_owned()
+                };
+                (tracker.registered_at, entry)
+            })
+            .collect();
+
+        entries.sort_by(|(a_time, a), (b_time, b)| {
+            a_time.cmp(b_time).then_with(|| a.path.cmp(&b.path))
+        });
+        entries.into_iter().map(|(_, entry)| entry).collect()
+    }
+
+    pub fn unregister(&self, id: &str) {
+        let trackers = &mut *self.file_trackers.lock().unwrap();
+        if let Some(tracker) = trackers.remove(id) {
+            self.save(trackers, &tracker.shelf);
+        }
+    }
+
+    pub fn register(&self, file: &str, shelf: &str, options: &RegisterOptions) -> RegisterResult {
+        let mut results = self.register_files(&[file.to_owned()], shelf, options);
+        results.pop().unwrap()
+    }
+
+    pub fn register_files(&self, files: &[String], shelf: &str, options: &RegisterOptions) -> Vec<RegisterResult> {
+        // Tagging and hashing happen outside the tracker locks, so concurrent
+        // batches are serialized to keep the duplicate and slot checks valid
+        // until the insert.
+        let _registering = self.registering.lock().unwrap();
+        let (mut results, accepted) = {
+            let file_trackers = &*self.file_trackers.lock().unwrap();
+            let registered: Vec<(&str, &FileIdentity, &str)> = file_trackers.iter()
+                .map(|(id, tracker)| (id.as_str(), &tracker.identity, tracker.shelf.as_str()))
+                .collect();
+            accept_files(files, shelf, &registered, self.get_slot_limit(shelf))
+        };
+        let accepted: Vec<(usize, &str, String)> = accepted.into_iter()
+            .map(|(index, file)| (index, file, get_scope_root(file, options.watch_scope)))
+            .collect();
+        let indices: Vec<usize> = accepted.iter().map(|(index, _, _)| *index).collect();
+
+        let watch_options = self.get_watch_options();
+        let mut dir_trackers = vec![];
+        {
+            let dir_trackers_map = &mut *self.dir_trackers.lock().unwrap();
+            for (index, _, root) in accepted.iter() {
+                match get_dir_tracker(dir_trackers_map, root, &watch_options) {
+                    Ok(dir_tracker) => {
+                        dir_trackers.push(dir_tracker);
+                    }
+                    Err(err) => {
+                        abort_batch(&mut results, files, &indices, *index, err);
+                        return results;
+                    }
+                }
+            }
+        }
+
+        let pending = match prepare_registrations(&accepted, options) {
+            Ok(pending) => pending,
+            Err((index, err)) => {
+                abort_batch(&mut results, files, &indices, index, err);
+                return results;
+            }
+        };
+
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        for ((index, registration), dir_tracker) in indices.iter().zip(pending).zip(dir_trackers) {
+            let file_id = registration.file_id.to_owned();
+            let weak = matches!(registration.identity, FileIdentity::Fingerprint(_));
+            file_trackers.insert(file_id.to_owned(), registration.into_tracker(dir_tracker, shelf));
+            results[*index] = Ok((file_id, weak));
+        }
+
+        self.save(file_trackers, shelf);
+        results
+    }
+
+    // Registers files by editing the saved shelf directly, for when no
+    // instance is running. Nothing is watched.
+    pub fn register_saved(&self, files: &[String], shelf: &str, shelves: &[String], options: &RegisterOptions) ->
+        Result<Vec<RegisterResult>, std::io::Error> {
+        let path = self.get_shelf_file(shelf)
+            .ok_or(std::io::Error::new(std::io::ErrorKind::Unsupported, "The bin is not persisted"))?;
+        let mut saved = vec![];
+        for other in shelves.iter() {
+            if let Some(other_path) = self.get_shelf_file(other) {
+                saved.push((other, read_shelf_file(&other_path)?));
+            }
+        }
+        let registered: Vec<(&str, &FileIdentity, &str)> = saved.iter()
+            .flat_map(|(other, entries)| {
+                entries.iter().map(|x| (x.id.as_str(), &x.identity, other.as_str()))
+            })
+            .collect();
+        let (mut results, accepted) = accept_files(files, shelf, &registered, self.get_slot_limit(shelf));
+        let accepted: Vec<(usize, &str, String)> = accepted.into_iter()
+            .map(|(index, file)| (index, file, String::new()))
+            .collect();
+        let indices: Vec<usize> = accepted.iter().map(|(index, _, _)| *index).collect();
+
+        let pending = match prepare_registrations(&accepted, options) {
+            Ok(pending) => pending,
+            Err((index, err)) => {
+                abort_batch(&mut results, files, &indices, index, err);
+                return Ok(results);
+            }
+        };
+
+        let mut entries = read_shelf_file(&path)?;
+        let registered_at = unix_millis();
+        for (index, registration) in indices.iter().zip(pending.iter()) {
+            entries.push(PersistedEntry {
+                id: registration.file_id.to_owned(),
+                path: registration.file.to_owned(),
+                registered_at,
+                identity: registration.identity.to_owned(),
+                content_hash: registration.content_hash.to_owned(),
+                unconfirmed: false,
+                deleted: false
+            });
+            results[*index] = Ok((
+                registration.file_id.to_owned(),
+                matches!(registration.identity, FileIdentity::Fingerprint(_))));
+        }
+        if let Err(err) = write_shelf_file(&path, &entries) {
+            for registration in pending.iter() {
+                registration.rollback();
+            }
+            return Err(err);
+        }
+        Ok(results)
+    }
+
+    // Removes an entry from the saved shelves directly, for when no instance
+    // is running.
+    pub fn unregister_saved(&self, id: &str, shelves: &[String]) -> Result<bool, std::io::Error> {
+        for shelf in shelves.iter() {
+            let path = match self.get_shelf_file(shelf) {
+                Some(path) => path,
+                None => {
+                    continue;
+                }
+            };
+            let mut entries = read_shelf_file(&path)?;
+            let count = entries.len();
+            entries.retain(|x| x.id != id);
+            if entries.len() != count {
+                write_shelf_file(&path, &entries)?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    pub fn recover<P>(
+        &self,
+        roots: &[String],
+        options: &RegisterOptions,
+        cancel: &AtomicBool,
+        mut progress: P) -> Vec<StateChange>
+    where P: FnMut(RecoveryProgress) {
+        let search_limit = self.get_search_options().search_limit;
+        let mut pending = vec![];
+        let mut recent_dirs: Vec<String> = vec![];
+        {
+            let file_trackers = &mut *self.file_trackers.lock().unwrap();
+            for (id, tracker) in file_trackers.iter_mut() {
+                let old_parent = get_existing_ancestor(&tracker.last_path);
+                if !recent_dirs.contains(&old_parent) {
+                    recent_dirs.push(old_parent.to_owned());
+                }
+
+                if let FileTrackerState::Lost { .. } = tracker.tracker_state {
+                    tracker.recovering = true;
+                    pending.push((id.to_owned(), tracker.identity.to_owned(), old_parent));
+                }
+            }
+        }
+
+        let mut found = vec![];
+        for (id, identity, old_parent) in pending.iter() {
+            let mut search_roots = vec![old_parent.to_owned()];
+            for root in recent_dirs.iter().chain(roots.iter()) {
+                if !search_roots.contains(root) {
+                    search_roots.push(root.to_owned());
+                }
+            }
+
+            let total = search_roots.len();
+            let mut truncated = vec![];
+            for (searched, root) in search_roots.into_iter().enumerate() {
+                if cancel.load(Ordering::Relaxed) {
+                    break;
+                }
+                progress(RecoveryProgress {
+                    id: id.to_owned(),
+                    root: root.to_owned(),
+                    searched,
+                    total,
+                    truncated: truncated.to_owned()
+                });
+
+                let res = find_file_cancellable(
+                    Path::new(&root),
+                    None,
+                    search_limit,
+                    cancel,
+                    |path| identity.matches(id, path));
+
+                match res {
+                    SearchResult::Found(path) => {
+                        found.push((id.to_owned(), path));
+                        truncated.clear();
+                        break;
+                    }
+                    SearchResult::Truncated => {
+                        truncated.push(root);
+                    }
+                    SearchResult::NotFound => {}
+                }
+            }
+
+            if !truncated.is_empty() {
+                progress(RecoveryProgress {
+                    id: id.to_owned(),
+                    root: String::new(),
+                    searched: total,
+                    total,
+                    truncated
+                });
+            }
+        }
+
+        self.commit_found(found, options, |file_trackers| {
+            for (id, _, _) in pending.iter() {
+                if let Some(tracker) = file_trackers.get_mut(id) {
+                    tracker.recovering = false;
+                    tracker.state_since = Instant::now();
+                }
+            }
+        })
+    }
+
+    fn commit_found<F>(&self, found: Vec<(String, String)>, options: &RegisterOptions, finish: F) -> Vec<StateChange>
+    where F: FnOnce(&mut FileTrackersMap) {
+        let mut changes = vec![];
+        let watch_options = self.get_watch_options();
+        let dir_trackers = &mut *self.dir_trackers.lock().unwrap();
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        finish(file_trackers);
+        for (id, path) in found {
+            let tracker = match file_trackers.get_mut(&id) {
+                Some(tracker) => tracker,
+                None => {
+                    continue;
+                }
+            };
+            if matches!(tracker.tracker_state, FileTrackerState::Certain { .. } | FileTrackerState::Deleted { .. }) {
+                continue;
+            }
+
+            let root = get_scope_root(&path, options.watch_scope);
+            let dir_tracker = match get_dir_tracker(dir_trackers, &root, &watch_options) {
+                Ok(dir_tracker) => dir_tracker,
+                Err(_) => {
+                    continue;
+                }
+            };
+
+            let old_state = tracker.tracker_state.to_owned();
+            tracker.dir_tracker = dir_tracker;
+            tracker.tracker_state = FileTrackerState::Certain {
+                id: id.to_owned(),
+                path: path.to_owned()
+            };
+            tracker.state_since = Instant::now();
+            tracker.last_path = path;
+            changes.push(StateChange {
+                id,
+                old: old_state,
+                new: tracker.tracker_state.to_owned(),
+                unconfirmed: tracker.unconfirmed
+            });
+        }
+
+        if !changes.is_empty() {
+            self.save_shelves(file_trackers, &changes);
+            self.notify(&changes);
+        }
+        changes
+    }
+
+    fn get_certain_path(&self, id: &str) -> Result<String, std::io::Error> {
+        let file_trackers = &*self.file_trackers.lock().unwrap();
+        let tracker = file_trackers.get(id)
+            .ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No entry with id {}", id)))?;
+        match &tracker.tracker_state {
+            FileTrackerState::Certain { path, .. } => Ok(path.to_owned()),
+            _ => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "The file's current location is unknown"))
+            }
+        }
+    }
+
+    fn set_state(&self, file_trackers: &mut FileTrackersMap, id: &str, state: FileTrackerState, unconfirmed: bool) -> Option<StateChange> {
+        let tracker = file_trackers.get_mut(id).unwrap();
+        if tracker.tracker_state == state && tracker.unconfirmed == unconfirmed {
+            return None;
+        }
+        tracker.unconfirmed = unconfirmed;
+        let old_state = std::mem::replace(&mut tracker.tracker_state, state);
+        tracker.state_since = Instant::now();
+        if let FileTrackerState::Certain { path, .. } = &tracker.tracker_state {
+            tracker.last_path = path.to_owned();
+        }
+
+        let change = StateChange {
+            id: id.to_owned(),
+            old: old_state,
+            new: tracker.tracker_state.to_owned(),
+            unconfirmed: tracker.unconfirmed
+        };
+        let shelf = tracker.shelf.to_owned();
+        self.save(file_trackers, &shelf);
+        self.notify(std::slice::from_ref(&change));
+        Some(change)
+    }
+
+    pub fn relocate(&self, id: &str, path: &str, options: &RegisterOptions) -> Result<Option<StateChange>, std::io::Error> {
+        let watch_options = self.get_watch_options();
+        let dir_trackers = &mut *self.dir_trackers.lock().unwrap();
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        let tracker = file_trackers.get_mut(id)
+            .ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No entry with id {}", id)))?;
+
+        if !tracker.refresh_identity(id, path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Can't identify {}", path)));
+        }
+        tracker.dir_tracker = get_dir_tracker(dir_trackers, &get_scope_root(path, options.watch_scope), &watch_options)?;
+
+        let state = FileTrackerState::Certain {
+            id: id.to_owned(),
+            path: path.to_owned()
+        };
+        let unconfirmed = tracker.unconfirmed;
+        Ok(self.set_state(file_trackers, id, state, unconfirmed))
+    }
+
+    pub fn confirm(&self, id: &str) -> Result<Option<StateChange>, std::io::Error> {
+        let path = self.get_certain_path(id)?;
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        let tracker = file_trackers.get_mut(id)
+            .ok_or(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No entry with id {}", id)))?;
+        if !tracker.unconfirmed {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "The entry doesn't need confirming"));
+        }
+
+        if write_tag(&path, id).is_ok() {
+            tracker.identity = FileIdentity::Tag;
+        }
+        let state = tracker.tracker_state.to_owned();
+        Ok(self.set_state(file_trackers, id, state, false))
+    }
+
+    pub fn copy_file(&self, id: &str, dir: &str) -> Result<String, std::io::Error> {
+        let path = self.get_certain_path(id)?;
+        copy_to_dir(&path, dir)
+    }
+
+    pub fn move_file(&self, id: &str, dir: &str, options: &RegisterOptions) ->
+        Result<(Option<StateChange>, Option<std::io::Error>), std::io::Error> {
+        let path = self.get_certain_path(id)?;
+        match move_to_dir(&path, dir) {
+            Ok(new_path) => {
+                Ok((self.relocate(id, &new_path, options)?, None))
+            }
+            Err(MoveError::Failed(err)) => Err(err),
+            Err(MoveError::SourceLeft { target, error }) => {
+                let change = self.relocate(id, &target, options)?;
+                let warning = std::io::Error::new(
+                    error.kind(),
+                    format!("Moved to {}, but couldn't remove {}: {}", target, path, error));
+                Ok((change, Some(warning)))
+            }
+        }
+    }
+
+    pub fn rename_file(&self, id: &str, name: &str, options: &RegisterOptions) -> Result<Option<StateChange>, std::io::Error> {
+        let path = self.get_certain_path(id)?;
+        let new_path = rename_in_place(&path, name)?;
+        self.relocate(id, &new_path, options)
+    }
+
+    pub fn trash_file(&self, id: &str) -> Result<Option<StateChange>, std::io::Error> {
+        let path = self.get_certain_path(id)?;
+        move_to_trash(&path)?;
+
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        let unconfirmed = match file_trackers.get(id) {
+            Some(tracker) => tracker.unconfirmed,
+            None => {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("No entry with id {}", id)));
+            }
+        };
+        let state = FileTrackerState::Deleted {
+            id: id.to_owned()
+        };
+        Ok(self.set_state(file_trackers, id, state, unconfirmed))
+    }
+
+    pub fn state(&self, id: &str) -> Option<FileTrackerState> {
+        let trackers = &mut *self.file_trackers.lock().unwrap();
+        let state = trackers.get(id);
+        state.map(|x| {
+            x.tracker_state.to_owned()
+        })
+    }
+
+    fn queue_searches(&self, jobs: Vec<SearchJob>) {
+        if jobs.is_empty() {
+            return;
+        }
+        self.searches.lock().unwrap().extend(jobs);
+        self.searches_ready.notify_all();
+    }
+
+    pub fn run_searches(&self, options: &RegisterOptions, timeout: Duration) -> Vec<StateChange> {
+        let search_limit = self.get_search_options().search_limit;
+        let jobs = {
+            let searches = self.searches.lock().unwrap();
+            let (mut searches, _) = self.searches_ready
+                .wait_timeout_while(searches, timeout, |x| x.is_empty())
+                .unwrap();
+            std::mem::take(&mut *searches)
+        };
+
+        let mut changes = vec![];
+        for job in jobs {
+            let found = search_for(&job, search_limit);
+            changes.extend(self.commit_found(found, options, |file_trackers| {
+                for (id, _) in job.wanted.iter() {
+                    if let Some(tracker) = file_trackers.get_mut(id) {
+                        tracker.searching = false;
+                        tracker.searched_at = Instant::now();
+                    }
+                }
+            }));
+        }
+        changes
+    }
+
+    pub fn tick(&self) -> TickResult {
+        let watch_options = self.get_watch_options();
+        let grace_periods = self.get_grace_periods();
+        let search_options = self.get_search_options();
+        let mut jobs = vec![];
+        let mut errors = vec![];
+        let mut ids = vec![];
+        let mut candidates = vec![];
+        let mut modified = vec![];
+        let dir_trackers = &mut *self.dir_trackers.lock().unwrap();
+        for (id, tracker) in dir_trackers.iter() {
+            let tracker = tracker.upgrade();
+            if let Some(tracker_ref) = tracker {
+                let tracker = &mut *tracker_ref.lock().unwrap();
+                tracker.expire_events();
+                tracker.overflowed = false;
+                tracker.read_events(&watch_options, &mut errors);
+                if tracker.abandoned {
+                    ids.push(id.to_owned());
+                }
+                for timed in tracker.fresh_events() {
+                    match &timed.event {
+                        FSEvent::FileAdded(path) |
+                        FSEvent::FileRenamedNew(path) => {
+                            candidates.push((path.to_string_lossy().into_owned(), tracker_ref.clone()));
+                        }
+                        FSEvent::FileModified(path) => {
+                            modified.push((path.to_string_lossy().into_owned(), tracker_ref.clone()));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            else {
+                ids.push(id.to_owned());
+            }
+        }
+
+        for id in ids.iter() {
+            dir_trackers.remove(id);
+        }
+
+        // Only a content hash can recognise a file by a write to it
+        let hash_candidates = [&candidates[..], &modified[..]].concat();
+        let mut changes = vec![];
+        let file_trackers = &mut *self.file_trackers.lock().unwrap();
+        for (id, tracker) in file_trackers.iter_mut() {
+            let old_state = tracker.tracker_state.to_owned();
+            tracker.update_state();
+            let overflowed_root = {
+                let dir_tracker = tracker.dir_tracker.lock().unwrap();
+                dir_tracker.overflowed.then(|| dir_tracker.root.to_owned())
+            };
+            if let Some(root) = overflowed_root {
+                if let Some(wanted) = tracker.resync() {
+                    match jobs.iter_mut().find(|x: &&mut SearchJob| x.skip.is_none() && x.root == root) {
+                        Some(job) => {
+                            job.wanted.push(wanted);
+                        }
+                        None => {
+                            jobs.push(SearchJob {
+                                root,
+                                skip: None,
+                                wanted: vec![wanted]
+                            });
+                        }
+                    }
+                }
+            }
+            let tracker_candidates = match tracker.content_hash {
+                Some(_) => &hash_candidates,
+                None => &candidates
+            };
+            tracker.reconcile(tracker_candidates);
+            if std::mem::discriminant(&old_state) != std::mem::discriminant(&tracker.tracker_state) {
+                tracker.state_since = Instant::now();
+            }
+            tracker.check_grace_periods(&grace_periods);
+            if let FileTrackerState::Certain { .. } = tracker.tracker_state {
+                tracker.widened = 0;
+                tracker.search_root = None;
+            }
+            jobs.extend(tracker.widen_scope(&grace_periods, &search_options));
+
+            if old_state != tracker.tracker_state {
+                if let FileTrackerState::Certain { path, .. } = &tracker.tracker_state {
+                    tracker.last_path = path.to_owned();
+                }
+                changes.push(StateChange {
+                    id: id.to_owned(),
+                    old: old_state,
+                    new: tracker.tracker_state.to_owned(),
+                    unconfirmed: tracker.unconfirmed
+                });
+            }
+        }
+
+        if !changes.is_empty() {
+            self.save_shelves(file_trackers, &changes);
+            self.notify(&changes);
+        }
+        self.queue_searches(jobs);
+        TickResult {
+            changes,
+            errors
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    fn parent_scope() -> RegisterOptions {
+        RegisterOptions {
+            content_hash: false,
+            watch_scope: WatchScope::Parent
+        }
+    }
+
+    fn idle_dir_tracker(root: &Path) -> Arc<Mutex<DirTracker>> {
+        Arc::new(Mutex::new(DirTracker {
+            root: root.to_string_lossy().into_owned(),
+            iter: None,
+            retry_at: Instant::now() + Duration::from_secs(3600),
+            failures: 0,
+            abandoned: false,
+            reported: vec![],
+            events: VecDeque::new(),
+            fresh: 0,
+            overflowed: false
+        }))
+    }
+
+    fn fingerprinted_tracker(file: &Path, dir_tracker: &Arc<Mutex<DirTracker>>) -> (String, FileTracker) {
+        let root = dir_tracker.lock().unwrap().root.to_owned();
+        let registration = PendingRegistration {
+            file: file.to_string_lossy().into_owned(),
+            file_id: new_file_id(),
+            identity: FileIdentity::Fingerprint(get_fingerprint(file.to_str().unwrap()).unwrap()),
+            content_hash: None,
+            root
+        };
+        let id = registration.file_id.to_owned();
+        (id, registration.into_tracker(dir_tracker.clone(), DEFAULT_SHELF))
+    }
+
+    #[test]
+    fn deleted_only_when_gone() {
+        let dir = temp_dir("deleted");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+
+        let dir_tracker = idle_dir_tracker(&dir);
+        let (id, mut tracker) = fingerprinted_tracker(&file, &dir_tracker);
+        let lost = FileTrackerState::Lost {
+            id,
+            partial_path: String::new(),
+            since: 0
+        };
+        tracker.tracker_state = lost.to_owned();
+        let grace_periods = GracePeriods {
+            lost: Duration::ZERO,
+            deleted: Duration::ZERO
+        };
+
+        tracker.check_grace_periods(&grace_periods);
+        let path = file.to_string_lossy().into_owned();
+        assert!(matches!(&tracker.tracker_state, FileTrackerState::Certain { path: x, .. } if *x == path));
+
+        let moved = dir.join("b.txt").to_string_lossy().into_owned();
+        std::fs::rename(&file, &moved).unwrap();
+        tracker.tracker_state = lost;
+        tracker.check_grace_periods(&grace_periods);
+        assert!(matches!(tracker.tracker_state, FileTrackerState::Deleted { .. }));
+
+        tracker.state_since = Instant::now() - DELETED_RECONCILE;
+        tracker.reconcile(&[(moved.to_owned(), dir_tracker.clone())]);
+        assert!(matches!(tracker.tracker_state, FileTrackerState::Deleted { .. }));
+
+        tracker.state_since = Instant::now();
+        tracker.reconcile(&[(moved.to_owned(), dir_tracker.clone())]);
+        assert!(matches!(&tracker.tracker_state, FileTrackerState::Certain { path, .. } if *path == moved));
+    }
+
+    #[test]
+    fn tick_follows_a_rename() {
+        let dir = temp_dir("tick");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let renamed = dir.join("b.txt").to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        registry.set_grace_periods(GracePeriods {
+            lost: Duration::from_secs(60),
+            deleted: Duration::from_secs(60)
+        });
+        let (id, _) = registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        std::fs::rename(&file, &renamed).unwrap();
+
+        let mut changes = vec![];
+        for _ in 0..50 {
+            changes.extend(registry.tick().changes);
+            if matches!(registry.state(&id), Some(FileTrackerState::Certain { .. })) && !changes.is_empty() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(matches!(registry.state(&id), Some(FileTrackerState::Certain { path, .. }) if path == renamed));
+        assert!(matches!(&changes.last().unwrap().new, FileTrackerState::Certain { path, .. } if *path == renamed));
+        assert!(changes.iter().all(|x| x.id == id));
+    }
+
+    #[test]
+    fn unchanged_state_is_not_reported() {
+        let dir = temp_dir("unchanged");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let path = file.to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        let (id, _) = registry.register(&path, DEFAULT_SHELF, &parent_scope()).unwrap();
+        let changes = registry.subscribe();
+        assert!(registry.relocate(&id, &path, &parent_scope()).unwrap().is_none());
+        assert!(changes.try_recv().is_err());
+
+        registry.file_trackers.lock().unwrap().get_mut(&id).unwrap().unconfirmed = true;
+        let change = registry.confirm(&id).unwrap().unwrap();
+        assert!(!change.unconfirmed);
+        assert_eq!(change.old, change.new);
+        assert_eq!(changes.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn registries_are_independent() {
+        let dir = temp_dir("independent");
+        let first_file = dir.join("a.txt");
+        let second_file = dir.join("b.txt");
+        std::fs::write(&first_file, "a").unwrap();
+        std::fs::write(&second_file, "b").unwrap();
+
+        let first = TrackerRegistry::with_persistence(dir.join("first.json"));
+        let second = TrackerRegistry::with_persistence(dir.join("second.json"));
+        let (first_id, _) = first.register(first_file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        let (second_id, _) = second.register(second_file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        assert!(first.state(&second_id).is_none());
+        assert!(second.state(&first_id).is_none());
+
+        let renamed = dir.join("c.txt").to_string_lossy().into_owned();
+        std::fs::rename(&first_file, &renamed).unwrap();
+        for _ in 0..50 {
+            first.tick();
+            second.tick();
+            if matches!(first.state(&first_id), Some(FileTrackerState::Certain { path, .. }) if path == renamed) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(matches!(first.state(&first_id), Some(FileTrackerState::Certain { path, .. }) if path == renamed));
+        let second_path = second_file.to_string_lossy().into_owned();
+        assert!(matches!(second.state(&second_id), Some(FileTrackerState::Certain { path, .. }) if path == second_path));
+
+        first.unregister(&first_id);
+        assert!(first.list(None).is_empty());
+        assert_eq!(second.list(None).len(), 1);
+        let (saved, _) = second.read_saved(&[DEFAULT_SHELF.into()]);
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].id, second_id);
+    }
+
+    #[test]
+    fn persistence_round_trip() {
+        let dir = temp_dir("persist");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let bin = dir.join("bin.json");
+
+        let registry = TrackerRegistry::with_persistence(bin.to_owned());
+        let (id, _) = registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        {
+            let file_trackers = &mut *registry.file_trackers.lock().unwrap();
+            file_trackers.get_mut(&id).unwrap().tracker_state = FileTrackerState::Deleted { id: id.to_owned() };
+            registry.save(file_trackers, DEFAULT_SHELF);
+        }
+        drop(registry);
+        assert!(!dir.join("bin.json.tmp").exists());
+
+        let restored = TrackerRegistry::with_persistence(bin);
+        assert!(restored.restore(&[DEFAULT_SHELF.into()], &parent_scope()).is_empty());
+        let entries = restored.list(None);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id, id);
+        assert_eq!(entries[0].path, file.to_str().unwrap());
+        assert!(matches!(entries[0].state, FileTrackerState::Deleted { .. }));
+    }
+
+    #[test]
+    fn read_saved_needs_no_watchers() {
+        let dir = temp_dir("read-saved");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let bin = dir.join("bin.json");
+
+        let registry = TrackerRegistry::with_persistence(bin.to_owned());
+        let (id, _) = registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        drop(registry);
+
+        let saved = TrackerRegistry::with_persistence(bin);
+        let (entries, errors) = saved.read_saved(&[DEFAULT_SHELF.into(), "other".into()]);
+        assert!(errors.is_empty());
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(&entries[0].state, FileTrackerState::Certain { path, .. } if *path == file.to_string_lossy()));
+        assert!(saved.dir_trackers.lock().unwrap().is_empty());
+        assert!(saved.list(None).is_empty());
+
+        std::fs::remove_file(&file).unwrap();
+        let (entries, _) = saved.read_saved(&[DEFAULT_SHELF.into()]);
+        assert!(matches!(&entries[0].state, FileTrackerState::Lost { id: lost, .. } if *lost == id));
+    }
+
+    #[test]
+    fn corrupt_bin_is_kept() {
+        let dir = temp_dir("corrupt");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let bin = dir.join("bin.json");
+        std::fs::write(&bin, "[{").unwrap();
+
+        let registry = TrackerRegistry::with_persistence(bin.to_owned());
+        assert_eq!(registry.restore(&[DEFAULT_SHELF.into()], &parent_scope()).len(), 1);
+        registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        assert_eq!(std::fs::read_to_string(&bin).unwrap(), "[{");
+        assert_eq!(registry.restore(&[DEFAULT_SHELF.into()], &parent_scope()).len(), 1);
+
+        let backups = std::fs::read_dir(&dir).unwrap()
+            .flatten()
+            .filter(|x| x.file_name().to_string_lossy().ends_with(".bak"))
+            .count();
+        assert_eq!(backups, 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn volume_scope_watches_the_parent_on_linux() {
+        let dir = std::env::temp_dir();
+        let file = dir.join("a.txt").to_string_lossy().into_owned();
+        assert_eq!(get_scope_root(&file, WatchScope::Volume), dir.to_string_lossy());
+    }
+
+    #[test]
+    fn recovery_reports_truncated_searches() {
+        let dir = temp_dir("recover");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        for index in 0..10 {
+            std::fs::write(dir.join(format!("{}.txt", index)), "b").unwrap();
+        }
+        std::fs::create_dir_all(dir.join("x/y")).unwrap();
+        let moved = dir.join("x/y/a.txt").to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        let (id, _) = registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        std::fs::rename(&file, &moved).unwrap();
+        registry.file_trackers.lock().unwrap().get_mut(&id).unwrap().tracker_state = FileTrackerState::Lost {
+            id: id.to_owned(),
+            partial_path: String::new(),
+            since: 0
+        };
+
+        let cancel = AtomicBool::new(false);
+        registry.set_search_options(SearchOptions {
+            search_limit: 3,
+            ..SearchOptions::default()
+        });
+        let mut progress = vec![];
+        let changes = registry.recover(&[], &parent_scope(), &cancel, |x| progress.push(x));
+        assert!(changes.is_empty());
+        let last = progress.last().unwrap();
+        assert_eq!(last.searched, last.total);
+        assert_eq!(last.truncated, [dir.to_string_lossy().into_owned()]);
+
+        registry.set_search_options(SearchOptions::default());
+        let changes = registry.recover(&[], &parent_scope(), &cancel, |_| {});
+        assert_eq!(changes.len(), 1);
+        assert!(matches!(registry.state(&id), Some(FileTrackerState::Certain { path, .. }) if path == moved));
+    }
+
+    #[test]
+    fn early_cancel_stops_recovery() {
+        let dir = temp_dir("cancel");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        std::fs::create_dir_all(dir.join("x")).unwrap();
+        let moved = dir.join("x/a.txt").to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        let (id, _) = registry.register(file.to_str().unwrap(), DEFAULT_SHELF, &parent_scope()).unwrap();
+        std::fs::rename(&file, &moved).unwrap();
+        registry.file_trackers.lock().unwrap().get_mut(&id).unwrap().tracker_state = FileTrackerState::Lost {
+            id: id.to_owned(),
+            partial_path: String::new(),
+            since: 0
+        };
+
+        let cancel = AtomicBool::new(true);
+        let mut progress = vec![];
+        let changes = registry.recover(&[], &parent_scope(), &cancel, |x| progress.push(x));
+        assert!(changes.is_empty());
+        assert!(progress.is_empty());
+        assert!(matches!(registry.state(&id), Some(FileTrackerState::Lost { .. })));
+        assert!(!registry.file_trackers.lock().unwrap()[&id].recovering);
+    }
+
+    #[test]
+    fn slow_subscribers_are_dropped() {
+        let registry = TrackerRegistry::new();
+        let slow = registry.subscribe();
+        let changes: Vec<StateChange> = (0..SUBSCRIBER_CAPACITY)
+            .map(|index| {
+                StateChange {
+                    id: index.to_string(),
+                    old: FileTrackerState::Deleted { id: index.to_string() },
+                    new: FileTrackerState::Deleted { id: index.to_string() },
+                    unconfirmed: false
+                }
+            })
+            .collect();
+        registry.notify(&changes);
+        let fast = registry.subscribe();
+        registry.notify(&changes[..1]);
+
+        assert_eq!(registry.subscribers.lock().unwrap().len(), 1);
+        assert_eq!(fast.try_iter().count(), 1);
+        assert_eq!(slow.iter().count(), SUBSCRIBER_CAPACITY);
+    }
+
+    #[test]
+    fn failing_watcher_is_reported_once_and_abandoned() {
+        let dir = temp_dir("abandon");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+
+        let dir_tracker = idle_dir_tracker(&dir.join("missing"));
+        let (id, mut tracker) = fingerprinted_tracker(&file, &dir_tracker);
+
+        let mut errors = vec![];
+        for _ in 0..DIR_TRACKER_RESTARTS + 2 {
+            let dir_tracker = &mut *dir_tracker.lock().unwrap();
+            dir_tracker.retry_at = Instant::now();
+            dir_tracker.read_events(&WatchOptions::default(), &mut errors);
+        }
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], FsMonError::BootFailed { .. }));
+        assert!(dir_tracker.lock().unwrap().abandoned);
+        assert_eq!(dir_tracker.lock().unwrap().failures, DIR_TRACKER_RESTARTS);
+
+        tracker.update_state();
+        assert!(matches!(&tracker.tracker_state, FileTrackerState::Lost { id: lost, .. } if *lost == id));
+    }
+
+    #[test]
+    fn rename_pairs_across_ticks() {
+        let dir = temp_dir("rename");
+        let old_path = dir.join("a.txt");
+        let new_path = dir.join("b.txt");
+        std::fs::write(&old_path, "a").unwrap();
+
+        let dir_tracker = idle_dir_tracker(&dir);
+        let (id, mut tracker) = fingerprinted_tracker(&old_path, &dir_tracker);
+
+        let push_event = |seq, event| {
+            let dir_tracker = &mut *dir_tracker.lock().unwrap();
+            dir_tracker.fresh = dir_tracker.events.len();
+            dir_tracker.events.push_back(TimedEvent {
+                seq,
+                time: Instant::now(),
+                event
+            });
+        };
+
+        std::fs::rename(&old_path, &new_path).unwrap();
+        push_event(1, FSEvent::FileRenamedOld(old_path.into_os_string()));
+        tracker.update_state();
+        assert!(matches!(tracker.tracker_state, FileTrackerState::Renaming { .. }));
+
+        push_event(2, FSEvent::FileRenamedNew(new_path.to_owned().into_os_string()));
+        tracker.update_state();
+        assert_eq!(tracker.tracker_state, FileTrackerState::Certain {
+            id,
+            path: new_path.to_string_lossy().into_owned()
+        });
+    }
+
+    #[test]
+    fn content_match_must_be_unique() {
+        let dir = temp_dir("content");
+        let content = vec![1u8; 1024];
+        let original = dir.join("a.bin");
+        std::fs::write(&original, &content).unwrap();
+
+        let dir_tracker = idle_dir_tracker(&dir);
+        let (id, mut tracker) = fingerprinted_tracker(&original, &dir_tracker);
+        tracker.content_hash = get_content_hash(original.to_str().unwrap());
+        tracker.tracker_state = FileTrackerState::Moving {
+            id,
+            partial_path: String::new()
+        };
+        std::fs::remove_file(&original).unwrap();
+
+        let first = dir.join("b.bin").to_string_lossy().into_owned();
+        let second = dir.join("c.bin").to_string_lossy().into_owned();
+        std::fs::write(&first, &content).unwrap();
+        std::fs::write(&second, &content).unwrap();
+        tracker.reconcile(&[(first.to_owned(), dir_tracker.clone()), (second.to_owned(), dir_tracker.clone())]);
+        assert!(matches!(tracker.tracker_state, FileTrackerState::Moving { .. }));
+
+        tracker.reconcile(&[(first.to_owned(), dir_tracker.clone()), (first.to_owned(), dir_tracker.clone())]);
+        assert!(matches!(&tracker.tracker_state, FileTrackerState::Certain { path, .. } if *path == first));
+        assert!(tracker.unconfirmed);
+        assert_eq!(get_tag(&first), None);
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let dir = temp_dir("duplicates");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let file = file.to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| registry.register(&file, DEFAULT_SHELF, &parent_scope())))
+                .collect();
+            threads.into_iter().map(|x| x.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|x| x.is_ok()).count(), 1);
+        assert_eq!(registry.list(None).len(), 1);
+
+        let (id, _) = results.into_iter().find_map(|x| x.ok()).unwrap();
+        remove_tag(&file);
+        registry.file_trackers.lock().unwrap().get_mut(&id).unwrap().identity =
+            FileIdentity::Fingerprint(get_fingerprint(&file).unwrap());
+        let err = registry.register(&file, "other", &parent_scope()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(registry.list(None).len(), 1);
+    }
+
+    #[test]
+    fn full_shelf_rejects_registrations() {
+        let dir = temp_dir("slots");
+        let files: Vec<String> = ["a.txt", "b.txt", "c.txt"].iter()
+            .map(|name| {
+                let file = dir.join(name);
+                std::fs::write(&file, name).unwrap();
+                file.to_string_lossy().into_owned()
+            })
+            .collect();
+
+        let registry = TrackerRegistry::new();
+        registry.set_slot_limits(HashMap::from([(DEFAULT_SHELF.to_owned(), 2)]));
+        registry.register(&files[0], DEFAULT_SHELF, &parent_scope()).unwrap();
+        let results = registry.register_files(&files[1..], DEFAULT_SHELF, &parent_scope());
+        assert!(results[0].is_ok());
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), std::io::ErrorKind::QuotaExceeded);
+        assert_eq!(get_tag(&files[2]), None);
+        assert!(registry.register(&files[2], "other", &parent_scope()).is_ok());
+    }
+
+    #[test]
+    fn saved_shelves_are_edited_directly() {
+        let dir = temp_dir("saved");
+        let file = dir.join("a.txt");
+        let other_file = dir.join("b.txt");
+        std::fs::write(&file, "a").unwrap();
+        std::fs::write(&other_file, "b").unwrap();
+        let file = file.to_string_lossy().into_owned();
+        let other_file = other_file.to_string_lossy().into_owned();
+        let shelves = [DEFAULT_SHELF.to_owned(), "other".to_owned()];
+
+        let registry = TrackerRegistry::with_persistence(dir.join("bin.json"));
+        registry.set_slot_limits(HashMap::from([("other".to_owned(), 1)]));
+        let results = registry.register_saved(&[file.to_owned()], DEFAULT_SHELF, &shelves, &parent_scope()).unwrap();
+        let (id, _) = results[0].as_ref().unwrap().to_owned();
+        let results = registry.register_saved(&[file.to_owned(), other_file.to_owned()], "other", &shelves, &parent_scope()).unwrap();
+        assert_eq!(results[0].as_ref().unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert!(results[1].is_ok());
+        let results = registry.register_saved(&[dir.join("bin.json").to_string_lossy().into_owned()], "other", &shelves, &parent_scope()).unwrap();
+        assert_eq!(results[0].as_ref().unwrap_err().kind(), std::io::ErrorKind::QuotaExceeded);
+        assert!(registry.dir_trackers.lock().unwrap().is_empty());
+
+        let (entries, _) = registry.read_saved(&shelves);
+        assert_eq!(entries.len(), 2);
+        assert!(matches!(&entries[0].state, FileTrackerState::Certain { path, .. } if *path == file));
+
+        assert!(registry.unregister_saved(&id, &shelves).unwrap());
+        assert!(!registry.unregister_saved(&id, &shelves).unwrap());
+        let (entries, _) = registry.read_saved(&shelves);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].shelf, "other");
+
+        std::fs::write(dir.join("bin.json"), "[{").unwrap();
+        assert!(registry.unregister_saved(&id, &shelves).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("bin.json")).unwrap(), "[{");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn failed_batch_registers_nothing() {
+        let dir = temp_dir("batch");
+        let file = dir.join("a.txt");
+        std::fs::write(&file, "a").unwrap();
+        let dangling = dir.join("dangling");
+        std::os::unix::fs::symlink(dir.join("missing"), &dangling).unwrap();
+        let file = file.to_string_lossy().into_owned();
+        let dangling = dangling.to_string_lossy().into_owned();
+
+        let registry = TrackerRegistry::new();
+        let results = registry.register_files(
+            &[file.to_owned(), file.to_owned(), dangling.to_owned()], DEFAULT_SHELF, &parent_scope());
+        assert_eq!(results.len(), 3);
+        assert!(results.iter().all(|x| x.is_err()));
+        assert_eq!(results[1].as_ref().unwrap_err().kind(), std::io::ErrorKind::AlreadyExists);
+        assert_eq!(results[0].as_ref().unwrap_err().kind(), std::io::ErrorKind::Interrupted);
+        assert!(registry.list(None).is_empty());
+        assert_eq!(get_tag(&file), None);
+
+        let results = registry.register_files(&[file.to_owned()], DEFAULT_SHELF, &parent_scope());
+        assert!(results[0].is_ok());
+        assert_eq!(registry.list(None).len(), 1);
+    }
+}
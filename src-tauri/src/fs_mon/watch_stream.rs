@@ -0,0 +1,166 @@
+use std::{pin::Pin, sync::{atomic::{AtomicBool, Ordering}, Arc}, task::{Context, Poll}, time::Duration};
+
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_stream::Stream;
+
+use super::fs_mon::{FSEvent, FSEventIter, FsMonError, WatchOptions};
+
+const WATCH_INTERVAL: Duration = Duration::from_millis(10);
+const WATCH_IDLE_INTERVAL: Duration = Duration::from_millis(500);
+const WATCH_CAPACITY: usize = 1024;
+
+fn run(root: String, options: WatchOptions, stop: Arc<AtomicBool>, sender: Sender<Result<FSEvent, FsMonError>>) {
+    let mut iter = match FSEventIter::with_options(&root, &options) {
+        Ok(iter) => iter,
+        Err(err) => {
+            let _ = sender.blocking_send(Err(err));
+            return;
+        }
+    };
+
+    // The wait doubles while nothing happens so an idle watch rarely wakes
+    let mut interval = WATCH_INTERVAL;
+    while !stop.load(Ordering::Relaxed) && !sender.is_closed() {
+        let res = iter.tick();
+        let mut idle = true;
+        while let Some(event) = iter.get_event() {
+            idle = false;
+            if sender.blocking_send(Ok(event)).is_err() {
+                return;
+            }
+        }
+        if let Err(err) = res {
+            let _ = sender.blocking_send(Err(err));
+            return;
+        }
+        interval = match idle {
+            true => std::cmp::min(interval * 2, WATCH_IDLE_INTERVAL),
+            false => WATCH_INTERVAL
+        };
+        std::thread::sleep(interval);
+    }
+}
+
+pub struct WatchStream {
+    receiver: Receiver<Result<FSEvent, FsMonError>>,
+    stop: Arc<AtomicBool>
+}
+
+impl Stream for WatchStream {
+    type Item = Result<FSEvent, FsMonError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl Drop for WatchStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.receiver.close();
+    }
+}
+
+pub fn watch(path: &str) -> WatchStream {
+    watch_with_options(path, &WatchOptions::default())
+}
+
+pub fn watch_with_options(path: &str, options: &WatchOptions) -> WatchStream {
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sender, receiver) = channel(WATCH_CAPACITY);
+    {
+        let root = path.to_owned();
+        let options = options.clone();
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            run(root, options, stop, sender);
+        });
+    }
+
+    WatchStream {
+        receiver,
+        stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[tokio::test]
+    async fn yields_events() {
+        let dir = temp_dir("stream");
+        let mut stream = watch(dir.to_str().unwrap());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let event = tokio::time::timeout(TIMEOUT, stream.next()).await.unwrap();
+        assert!(matches!(event, Some(Ok(FSEvent::FileAdded(name))) if name == "a.txt"));
+    }
+
+    #[tokio::test]
+    async fn ends_after_boot_failure() {
+        let dir = temp_dir("stream-missing");
+        let mut stream = watch(dir.join("missing").to_str().unwrap());
+        let event = tokio::time::timeout(TIMEOUT, stream.next()).await.unwrap();
+        assert!(matches!(event, Some(Err(FsMonError::BootFailed { .. }))));
+        assert!(tokio::time::timeout(TIMEOUT, stream.next()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn drop_stops_the_watcher() {
+        let dir = temp_dir("stream-drop");
+        let stream = watch(dir.to_str().unwrap());
+        let stop = stream.stop.clone();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(stream);
+
+        let stopped = tokio::time::timeout(TIMEOUT, async {
+            while Arc::strong_count(&stop) > 1 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(stopped.await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn slow_consumer_pauses_the_watcher() {
+        let dir = temp_dir("stream-slow");
+        let options = WatchOptions {
+            backend: super::super::fs_mon::WatchBackend::Polling,
+            poll_interval: Duration::from_millis(20),
+            ..WatchOptions::default()
+        };
+        let mut stream = watch_with_options(dir.to_str().unwrap(), &options);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        for index in 0..WATCH_CAPACITY + 10 {
+            std::fs::write(dir.join(format!("{}.txt", index)), "a").unwrap();
+        }
+        let full = tokio::time::timeout(TIMEOUT, async {
+            while stream.receiver.len() < WATCH_CAPACITY {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+        assert!(full.await.is_ok());
+        assert_eq!(stream.receiver.capacity(), 0);
+
+        let mut added = 0;
+        while added < WATCH_CAPACITY + 10 {
+            match tokio::time::timeout(TIMEOUT, stream.next()).await.unwrap() {
+                Some(Ok(FSEvent::FileAdded(_))) => {
+                    added += 1;
+                }
+                Some(Ok(FSEvent::FileModified(_))) => {}
+                event => {
+                    panic!("Unexpected event {:?}", event);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+
+use super::fs_mon::{FSEvent, FsMonError};
+
+pub enum FileSystemEvent {}
+
+type MonitorHandle = i32;
+const INVALID_MONITOR_HANDLE: MonitorHandle = 0;
+
+unsafe extern "C" {
+    fn Boot(dir: *const u8) -> MonitorHandle;
+    fn Tick(handle: MonitorHandle) -> bool;
+    fn Peek(handle: MonitorHandle) -> *const FileSystemEvent;
+    fn Pop(handle: MonitorHandle);
+    fn Shutdown(handle: MonitorHandle);
+
+    fn GetLastErr(len: *mut i32) -> *const u8;
+    fn GetAction(event: *const FileSystemEvent) -> i32;
+    fn GetFile(event: *const FileSystemEvent, size: *mut i32) -> *const u16;
+}
+
+
+fn get_last_error() -> String {
+    unsafe {
+        let mut l: i32 = 0;
+        let e = GetLastErr(&mut l as *mut i32);
+        let e = &*std::ptr::slice_from_raw_parts(e, l as usize);
+        let err = String::from_utf8_lossy(e);
+        err.to_string()
+    }
+}
+
+fn to_null_terminated(s: &str) -> Vec<u8> {
+    let src_bytes = s.as_bytes();
+    let final_size = src_bytes.len() + 1;
+    let mut res = vec![0; final_size];
+
+    let bytes = &mut res[..final_size - 1];
+    bytes.copy_from_slice(s.as_bytes());
+
+    res
+}
+
+/// fs_monitor opens its own directory handle per root, so there is nothing to
+/// share between roots.
+#[derive(Debug, Clone, Default)]
+pub struct SharedWatcher;
+
+pub struct NativeEventIter {
+    root: String,
+    handle: MonitorHandle
+}
+
+impl NativeEventIter {
+    pub fn new(root: &str, _shared: &SharedWatcher) -> Result<Self, FsMonError> {
+        let dir = to_null_terminated(root);
+        let handle = unsafe {
+            let dir = dir.as_ptr();
+            Boot(dir)
+        };
+
+        if handle == INVALID_MONITOR_HANDLE {
+            return Err(FsMonError::BootFailed {
+                root: root.into(),
+                message: get_last_error()
+            });
+        }
+        let res = NativeEventIter {
+            root: root.into(),
+            handle
+        };
+        Ok(res)
+    }
+
+    pub fn tick(&self) -> Result<(), FsMonError> {
+        let res = unsafe {
+            Tick(self.handle)
+        };
+        if !res {
+            return Err(FsMonError::HandleLost {
+                root: self.root.to_owned(),
+                message: get_last_error()
+            });
+        }
+        Ok(())
+    }
+
+    pub fn get_event(&self) -> Option<FSEvent> {
+        unsafe {
+            let evt = Peek(self.handle);
+            if evt.is_null() {
+                None
+            }
+            else {
+                let action = GetAction(evt);
+                let mut size: i32 = 0;
+                let file = GetFile(evt, &mut size as *mut i32);
+                let file = &*std::ptr::slice_from_raw_parts(file, size as usize);
+                let file = OsString::from_wide(file);
+
+                Pop(self.handle);
+
+                match action {
+                    1 => Some(FSEvent::FileAdded(file)),
+                    2 => Some(FSEvent::FileRemoved(file)),
+                    3 => Some(FSEvent::FileModified(file)),
+                    4 => Some(FSEvent::FileRenamedOld(file)),
+                    5 => Some(FSEvent::FileRenamedNew(file)),
+                    6 => Some(FSEvent::Overflow(file)),
+                    _ => None
+                }
+            }
+        }
+    }
+}
+
+impl Drop for NativeEventIter {
+    fn drop(&mut self) {
+        unsafe {
+            Shutdown(self.handle);
+        }
+    }
+}
+
+
@@ -0,0 +1,209 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct InstanceInfo {
+    pub pid: u32,
+    pub socket: String,
+    pub profile: Option<String>
+}
+
+pub struct InstanceLock {
+    _file: File,
+    info_path: PathBuf
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.info_path);
+    }
+}
+
+pub enum LockError {
+    Running(InstanceInfo),
+    Io(std::io::Error)
+}
+
+fn get_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+pub fn get_runtime_dir() -> Result<PathBuf, std::io::Error> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|x| !x.is_empty()) {
+        return Ok(runtime_dir.into());
+    }
+    let runtime_dir = std::env::temp_dir().join(format!("side_bin-{}", get_user()));
+    match std::fs::DirBuilder::new().mode(0o700).create(&runtime_dir) {
+        Err(err) if err.kind() != std::io::ErrorKind::AlreadyExists => {
+            return Err(err);
+        }
+        _ => {}
+    }
+    check_private_dir(&runtime_dir)?;
+    Ok(runtime_dir)
+}
+
+#[cfg(unix)]
+fn check_private_dir(dir: &Path) -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let meta = dir.symlink_metadata()?;
+    let uid = unsafe { libc::geteuid() };
+    if !meta.is_dir() || meta.uid() != uid || meta.mode() & 0o777 != 0o700 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} must be a directory owned by you with mode 0700", dir.display())));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_runtime_dir() -> Result<PathBuf, std::io::Error> {
+    let runtime_dir = std::env::temp_dir().join("side_bin");
+    std::fs::create_dir_all(&runtime_dir)?;
+    Ok(runtime_dir)
+}
+
+pub fn is_valid_profile(profile: &str) -> bool {
+    !profile.is_empty() && profile.chars().all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+pub fn get_instance_name(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("side_bin-{}", profile),
+        None => "side_bin".into()
+    }
+}
+
+#[cfg(unix)]
+pub fn get_socket_path(profile: Option<&str>) -> Result<String, std::io::Error> {
+    let socket = get_runtime_dir()?
+        .join(format!("{}.sock", get_instance_name(profile)))
+        .to_string_lossy()
+        .into_owned();
+    Ok(socket)
+}
+
+#[cfg(target_os = "windows")]
+pub fn get_socket_path(profile: Option<&str>) -> Result<String, std::io::Error> {
+    Ok(format!(r"\\.\pipe\{}-{}", get_instance_name(profile), get_user()))
+}
+
+fn get_lock_paths(profile: Option<&str>) -> Result<(PathBuf, PathBuf), std::io::Error> {
+    let name = get_instance_name(profile);
+    let runtime_dir = get_runtime_dir()?;
+    Ok((runtime_dir.join(format!("{}.lock", name)), runtime_dir.join(format!("{}.json", name))))
+}
+
+fn read_info(info_path: &PathBuf) -> Option<InstanceInfo> {
+    let content = std::fs::read_to_string(info_path).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+fn get_running_info(info_path: &PathBuf, profile: Option<&str>) -> InstanceInfo {
+    read_info(info_path).unwrap_or(InstanceInfo {
+        pid: 0,
+        socket: get_socket_path(profile).unwrap_or_default(),
+        profile: profile.map(|x| x.into())
+    })
+}
+
+pub fn acquire(profile: Option<&str>) -> Result<InstanceLock, LockError> {
+    let (lock_path, info_path) = get_lock_paths(profile).map_err(LockError::Io)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .map_err(LockError::Io)?;
+
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let info = get_running_info(&info_path, profile);
+            return Err(LockError::Running(info));
+        }
+        Err(TryLockError::Error(err)) => {
+            return Err(LockError::Io(err));
+        }
+    }
+
+    let info = InstanceInfo {
+        pid: std::process::id(),
+        socket: get_socket_path(profile).map_err(LockError::Io)?,
+        profile: profile.map(|x| x.into())
+    };
+    let content = serde_json::to_string(&info)
+        .map_err(|err| LockError::Io(err.into()))?;
+    std::fs::write(&info_path, content).map_err(LockError::Io)?;
+
+    Ok(InstanceLock {
+        _file: file,
+        info_path
+    })
+}
+
+pub fn find_running(profile: Option<&str>) -> Option<InstanceInfo> {
+    let (lock_path, info_path) = get_lock_paths(profile).ok()?;
+    let file = OpenOptions::new()
+        .read(true)
+        .open(&lock_path)
+        .ok()?;
+
+    match file.try_lock_shared() {
+        Ok(()) => None,
+        Err(TryLockError::WouldBlock) => Some(get_running_info(&info_path, profile)),
+        Err(TryLockError::Error(_)) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn find_running_leaves_no_trace() {
+        let profile = format!("test-{}", std::process::id());
+        let profile = Some(profile.as_str());
+        let (lock_path, info_path) = get_lock_paths(profile).unwrap();
+        let _ = std::fs::remove_file(&lock_path);
+
+        assert!(find_running(profile).is_none());
+        assert!(!lock_path.exists());
+
+        let lock = acquire(profile).ok().unwrap();
+        let info = find_running(profile).unwrap();
+        assert_eq!(info.pid, std::process::id());
+        assert!(info_path.exists());
+        assert!(matches!(acquire(profile), Err(LockError::Running(_))));
+
+        drop(lock);
+        assert!(find_running(profile).is_none());
+        let _ = std::fs::remove_file(&lock_path);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runtime_dir_must_be_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("private");
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(check_private_dir(&dir).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(check_private_dir(&dir).is_ok());
+
+        let link = dir.with_extension("link");
+        let _ = std::fs::remove_file(&link);
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(check_private_dir(&link).is_err());
+        let _ = std::fs::remove_file(&link);
+    }
+}
@@ -0,0 +1,361 @@
+use std::io::{BufRead, BufReader, Write};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex};
+
+use interprocess::local_socket::{prelude::*, GenericFilePath, ListenerOptions, Name, SendHalf, Stream};
+use serde_json::{json, Value};
+
+use crate::{Entry, RegisterOptions, StateChange, TrackerRegistry, DEFAULT_SHELF};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const APP_ERROR: i64 = -32000;
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into()
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value
+}
+
+fn socket_name(socket: &str) -> Result<Name<'_>, std::io::Error> {
+    socket.to_fs_name::<GenericFilePath>()
+}
+
+pub fn register_results_json(files: &[String], results: Vec<Result<(String, bool), std::io::Error>>) -> Value {
+    let results: Vec<Value> = files.iter()
+        .zip(results)
+        .map(|(file, res)| {
+            match res {
+                Ok((file_id, weak)) => {
+                    json!({
+                        "file": file,
+                        "id": file_id,
+                        "weak": weak
+                    })
+                }
+                Err(err) => {
+                    json!({
+                        "file": file,
+                        "error": err.to_string()
+                    })
+                }
+            }
+        })
+        .collect();
+
+    json!({
+        "results": results
+    })
+}
+
+fn get_param<T: serde::de::DeserializeOwned>(params: &Value, key: &str) -> Result<T, RpcError> {
+    let value = params.get(key)
+        .ok_or(RpcError::new(INVALID_PARAMS, format!("Missing parameter \"{}\"", key)))?;
+
+    serde_json::from_value(value.to_owned())
+        .map_err(|err| RpcError::new(INVALID_PARAMS, format!("Invalid parameter \"{}\": {}", key, err)))
+}
+
+fn get_optional_param<T: serde::de::DeserializeOwned>(params: &Value, key: &str) -> Result<Option<T>, RpcError> {
+    match params.get(key) {
+        Some(Value::Null) | None => Ok(None),
+        Some(_) => get_param(params, key).map(Some)
+    }
+}
+
+fn get_shelf_param(params: &Value, shelves: &[String]) -> Result<Option<String>, RpcError> {
+    let shelf: Option<String> = get_optional_param(params, "shelf")?;
+    match shelf {
+        Some(shelf) if !shelves.contains(&shelf) => {
+            Err(RpcError::new(APP_ERROR, format!("No shelf named \"{}\"", shelf)))
+        }
+        shelf => Ok(shelf)
+    }
+}
+
+pub fn dispatch(
+    registry: &TrackerRegistry,
+    options: &RegisterOptions,
+    shelves: &[String],
+    method: &str,
+    params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "register" => {
+            let paths: Vec<String> = get_param(params, "paths")?;
+            let shelf = get_shelf_param(params, shelves)?.unwrap_or(DEFAULT_SHELF.into());
+            let results = registry.register_files(&paths, &shelf, options);
+            let mut response = register_results_json(&paths, results);
+            response["shelf"] = json!(shelf);
+            Ok(response)
+        }
+        "unregister" => {
+            let id: String = get_param(params, "id")?;
+            if registry.state(&id).is_none() {
+                return Err(RpcError::new(APP_ERROR, format!("No entry with id {}", id)));
+            }
+            registry.unregister(&id);
+            Ok(json!({
+                "removed": id
+            }))
+        }
+        "list" | "state" | "reveal" => {
+            dispatch_entries(registry.list(None), shelves, method, params)
+        }
+        _ => {
+            Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method \"{}\"", method)))
+        }
+    }
+}
+
+// Like dispatch, but edits the saved bin directly for when no instance is
+// running.
+pub fn dispatch_saved(
+    registry: &TrackerRegistry,
+    options: &RegisterOptions,
+    shelves: &[String],
+    method: &str,
+    params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "register" => {
+            let paths: Vec<String> = get_param(params, "paths")?;
+            let shelf = get_shelf_param(params, shelves)?.unwrap_or(DEFAULT_SHELF.into());
+            let results = registry.register_saved(&paths, &shelf, shelves, options)
+                .map_err(|err| RpcError::new(APP_ERROR, format!("Can't update the bin: {}", err)))?;
+            let mut response = register_results_json(&paths, results);
+            response["shelf"] = json!(shelf);
+            Ok(response)
+        }
+        "unregister" => {
+            let id: String = get_param(params, "id")?;
+            let removed = registry.unregister_saved(&id, shelves)
+                .map_err(|err| RpcError::new(APP_ERROR, format!("Can't update the bin: {}", err)))?;
+            if !removed {
+                return Err(RpcError::new(APP_ERROR, format!("No entry with id {}", id)));
+            }
+            Ok(json!({
+                "removed": id
+            }))
+        }
+        _ => {
+            let (entries, errors) = registry.read_saved(shelves);
+            for err in errors {
+                eprintln!("Can't read the bin: {}", err);
+            }
+            dispatch_entries(entries, shelves, method, params)
+        }
+    }
+}
+
+pub fn dispatch_entries(entries: Vec<Entry>, shelves: &[String], method: &str, params: &Value) -> Result<Value, RpcError> {
+    match method {
+        "list" => {
+            let shelf = get_shelf_param(params, shelves)?;
+            let entries: Vec<Entry> = entries.into_iter()
+                .filter(|x| shelf.as_ref().is_none_or(|shelf| x.shelf.eq(shelf)))
+                .collect();
+            Ok(json!({
+                "entries": entries
+            }))
+        }
+        "state" => {
+            let id: String = get_param(params, "id")?;
+            match entries.into_iter().find(|x| x.id.eq(&id)) {
+                Some(entry) => Ok(json!(entry.state)),
+                None => Err(RpcError::new(APP_ERROR, format!("No entry with id {}", id)))
+            }
+        }
+        "reveal" => {
+            let id: String = get_param(params, "id")?;
+            let entry = entries.into_iter()
+                .find(|x| x.id.eq(&id))
+                .ok_or(RpcError::new(APP_ERROR, format!("No entry with id {}", id)))?;
+
+            opener::reveal(&entry.path)
+                .map_err(|err| RpcError::new(APP_ERROR, err.to_string()))?;
+            Ok(json!({
+                "revealed": entry.path
+            }))
+        }
+        _ => {
+            Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method \"{}\"", method)))
+        }
+    }
+}
+
+fn write_message(writer: &Mutex<SendHalf>, message: &Value) -> Result<(), std::io::Error> {
+    let writer = &mut *writer.lock().unwrap();
+    writer.write_all(format!("{}\n", message).as_bytes())
+}
+
+fn forward_changes(writer: Arc<Mutex<SendHalf>>, changes: Receiver<StateChange>) {
+    for change in changes {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "stateChanged",
+            "params": change
+        });
+        if write_message(&writer, &notification).is_err() {
+            return;
+        }
+    }
+
+    let notification = json!({
+        "jsonrpc": "2.0",
+        "method": "unsubscribed",
+        "params": {
+            "reason": "Fell too far behind on state changes"
+        }
+    });
+    let _ = write_message(&writer, &notification);
+}
+
+fn handle_request<H, S>(line: &str, writer: &Arc<Mutex<SendHalf>>, handler: &H, subscribe: &S) -> Option<Value>
+where H: Fn(&str, &Value) -> Result<Value, RpcError>,
+      S: Fn() -> Receiver<StateChange> {
+    let request: RpcRequest = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(err) => {
+            let code = match serde_json::from_str::<Value>(line) {
+                Ok(_) => INVALID_REQUEST,
+                Err(_) => PARSE_ERROR
+            };
+            return Some(json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": RpcError::new(code, err.to_string())
+            }));
+        }
+    };
+
+    let res = match (request.jsonrpc.as_str(), request.method.as_str()) {
+        ("2.0", "subscribe") => {
+            let changes = subscribe();
+            let writer = writer.clone();
+            std::thread::spawn(move || {
+                forward_changes(writer, changes);
+            });
+            Ok(json!({
+                "subscribed": true
+            }))
+        }
+        ("2.0", method) => handler(method, &request.params),
+        _ => Err(RpcError::new(INVALID_REQUEST, "Expected \"jsonrpc\": \"2.0\""))
+    };
+
+    let id = request.id?;
+    let response = match res {
+        Ok(result) => {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "result": result
+            })
+        }
+        Err(error) => {
+            json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": error
+            })
+        }
+    };
+    Some(response)
+}
+
+fn handle_connection<H, S>(conn: Stream, handler: &H, subscribe: &S)
+where H: Fn(&str, &Value) -> Result<Value, RpcError>,
+      S: Fn() -> Receiver<StateChange> {
+    let (reader, writer) = conn.split();
+    let writer = Arc::new(Mutex::new(writer));
+
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => {
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        if let Some(response) = handle_request(&line, &writer, handler, subscribe) {
+            if write_message(&writer, &response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+pub fn serve<H, S>(socket: &str, handler: H, subscribe: S) -> Result<(), std::io::Error>
+where H: Fn(&str, &Value) -> Result<Value, RpcError> + Send + Sync + 'static,
+      S: Fn() -> Receiver<StateChange> + Send + Sync + 'static {
+    let listener = ListenerOptions::new()
+        .name(socket_name(socket)?)
+        .try_overwrite(true)
+        .create_sync()?;
+
+    let handler = Arc::new(handler);
+    let subscribe = Arc::new(subscribe);
+    for conn in listener.incoming() {
+        let conn = match conn {
+            Ok(conn) => conn,
+            Err(_) => {
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        let subscribe = subscribe.clone();
+        std::thread::spawn(move || {
+            handle_connection(conn, &*handler, &*subscribe);
+        });
+    }
+    Ok(())
+}
+
+pub fn connect(socket: &str) -> Result<Stream, std::io::Error> {
+    Stream::connect(socket_name(socket)?)
+}
+
+pub fn call(conn: Stream, method: &str, params: Value) -> Result<Result<Value, RpcError>, std::io::Error> {
+    let mut conn = BufReader::new(conn);
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": method,
+        "params": params
+    });
+    conn.get_mut().write_all(format!("{}\n", request).as_bytes())?;
+
+    let mut line = String::new();
+    conn.read_line(&mut line)?;
+    let mut response: Value = serde_json::from_str(&line)?;
+
+    if let Some(error) = response.get("error") {
+        let error = serde_json::from_value(error.to_owned())?;
+        return Ok(Err(error));
+    }
+    Ok(Ok(response["result"].take()))
+}
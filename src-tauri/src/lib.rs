@@ -1,20 +1,51 @@
 use std::io::{BufWriter, Cursor};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
-use std::time::SystemTime;
+use std::time::{Duration, Instant};
 use std::{env, panic};
 
-use fs_mon::{file_tag::get_tag, trackers::{get_tracker_state, tick}};
+use config::{get_config_dirs, get_config_layers, get_user_config_dir, is_valid_shelf_name, load_layers, LoadedConfig};
+use fs_mon::file_tag::get_tag;
+use fs_mon::fs_mon::FSEventIter;
+pub use fs_mon::fs_mon::{FSEvent, FsMonError, WatchBackend, WatchOptions};
+pub use fs_mon::watch_stream::{watch, watch_with_options, WatchStream};
+use fs_mon::search::{get_dir_stats, DEFAULT_STATS_LIMIT};
 use image::ImageEncoder;
+use instance::LockError;
+use ipc::{register_results_json, RpcError};
 use serde_json::json;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Emitter, Manager, State, WebviewWindow};
+
+mod cli;
+mod config;
+mod instance;
+mod ipc;
+
+#[cfg(test)]
+mod test_util;
 
 mod fs_mon {
+    #[allow(clippy::module_inception)]
     pub mod fs_mon;
     pub mod file_tag;
+    pub mod content_hash;
+    pub mod file_ops;
+    pub mod search;
     pub mod trackers;
+
+    #[cfg(target_os = "windows")]
+    pub mod win_monitor;
+
+    #[cfg(target_os = "linux")]
+    pub mod inotify_monitor;
+
+    pub mod poll_monitor;
+    pub mod watch_stream;
 }
 
+pub use fs_mon::trackers::{Entry, FileTrackerState, GracePeriods, RecoveryProgress, RegisterOptions, SearchOptions, StateChange, TrackerRegistry, WatchScope, DEFAULT_SHELF};
+
 #[tauri::command]
 fn exit_app(app: AppHandle) {
     app.exit(0);
@@ -37,151 +68,381 @@ fn open_file_directory(file: String) {
 }
 
 fn get_config_file_path(filename: &str) -> Result<std::path::PathBuf, ()> {
-    fn get_file_in_exe_dir(filename: &str) -> Result<std::path::PathBuf, ()> {
-        let current_exe = std::env::current_exe()
-            .map_err(|_| ())?;
+    get_config_dirs().into_iter()
+        .rev()
+        .map(|(_, dir)| dir.join(filename))
+        .find(|path| path.exists())
+        .ok_or(())
+}
 
-        let parent = current_exe.parent()
-            .ok_or(())?;
+#[derive(Default)]
+struct CliArgs {
+    config: Option<std::path::PathBuf>,
+    profile: Option<String>,
+    shelf: Option<String>,
+    positional: Vec<String>
+}
+
+fn take_value<'a, I>(arg: &str, args: &mut std::iter::Peekable<I>) -> Result<String, String>
+where I: Iterator<Item = &'a String> {
+    args.next_if(|x| !x.starts_with("--") && !cli::is_command(x))
+        .cloned()
+        .ok_or(format!("Missing value for {}", arg))
+}
 
-        let path = parent.join(filename);
-        match path.exists() {
-            true => Ok(path),
-            false => Err(())
+fn parse_args(args: &[String]) -> Result<CliArgs, String> {
+    let mut res = CliArgs::default();
+    let mut args = args.iter().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            res.config = Some(take_value(arg, &mut args)?.into());
+        }
+        else if let Some(path) = arg.strip_prefix("--config=") {
+            res.config = Some(path.into());
+        }
+        else if arg == "--new-instance" {
+            res.profile = Some(take_value(arg, &mut args)?);
+        }
+        else if let Some(profile) = arg.strip_prefix("--new-instance=") {
+            res.profile = Some(profile.into());
+        }
+        else if arg == "--shelf" {
+            res.shelf = Some(take_value(arg, &mut args)?);
+        }
+        else if let Some(shelf) = arg.strip_prefix("--shelf=") {
+            res.shelf = Some(shelf.into());
+        }
+        else {
+            res.positional.push(arg.to_owned());
         }
     }
-    fn get_file_in_config_dir(filename: &str) -> Result<std::path::PathBuf, ()> {
-        let parent = env_home::env_home_dir()
-            .ok_or(())?
-            .join(".side_bin");
+    Ok(res)
+}
 
-        let path = parent.join(filename);
-        match path.exists() {
-            true => Ok(path),
-            false => Err(())
-        }
+fn get_bin_file_path(profile: Option<&str>) -> Option<std::path::PathBuf> {
+    let name = match profile {
+        Some(profile) => format!("bin-{}.json", profile),
+        None => "bin.json".into()
+    };
+    Some(get_user_config_dir()?.join(name))
+}
+
+const MAIN_WINDOW: &str = "main";
+const SHELF_WINDOW_PREFIX: &str = "shelf-";
+
+fn get_shelf(window: &WebviewWindow) -> String {
+    match window.label().strip_prefix(SHELF_WINDOW_PREFIX) {
+        Some(shelf) => shelf.into(),
+        None => DEFAULT_SHELF.into()
     }
+}
 
-    if let Ok(path) = get_file_in_exe_dir(filename) {
-        return Ok(path);
+fn get_shelf_label(shelf: &str) -> String {
+    match shelf {
+        DEFAULT_SHELF => MAIN_WINDOW.into(),
+        shelf => format!("{}{}", SHELF_WINDOW_PREFIX, shelf)
     }
+}
 
-    get_file_in_config_dir(filename)
+#[derive(Default)]
+struct ConfigCache {
+    cli_config: Option<std::path::PathBuf>,
+    config: Mutex<LoadedConfig>,
+    style: Mutex<Option<String>>
 }
 
-static CONFIG: Mutex<Option<(SystemTime, String)>> = Mutex::new(None);
-fn read_config_internal() -> Result<String, ()> {
-    let config = get_config_file_path("config.json")?;
-    let meta = config.metadata()
-        .map_err(|_| ())?;
+fn load_config(cache: &ConfigCache) -> LoadedConfig {
+    let layers = get_config_layers(cache.cli_config.as_deref());
+    let loaded = load_layers(&layers);
+    for error in loaded.errors.iter() {
+        eprintln!("config.json: {}: {}", error.key, error.message);
+    }
+    for warning in loaded.warnings.iter() {
+        eprintln!("config.json: {}: {}", warning.key, warning.message);
+    }
+    loaded
+}
 
-    let modified = meta.modified()
-        .map_err(|_| ())?;
+fn load_style() -> Option<String> {
+    let style = get_config_file_path("style.css").ok()?;
+    std::fs::read_to_string(style).ok()
+}
 
-    let config_obj = &mut *CONFIG.lock().unwrap();
-    let dur = match config_obj {
-        None => {
-            None
+fn reload_config(cache: &ConfigCache) -> Option<LoadedConfig> {
+    let loaded = load_config(cache);
+    let config_obj = &mut *cache.config.lock().unwrap();
+    if loaded == *config_obj {
+        return None;
+    }
+    *config_obj = loaded.to_owned();
+    Some(loaded)
+}
+
+fn reload_style(cache: &ConfigCache) -> Option<Option<String>> {
+    let style = load_style();
+    let style_obj = &mut *cache.style.lock().unwrap();
+    if style == *style_obj {
+        return None;
+    }
+    *style_obj = style.to_owned();
+    Some(style)
+}
+
+fn read_config_internal(cache: &ConfigCache) -> LoadedConfig {
+    cache.config.lock().unwrap().to_owned()
+}
+
+fn read_style_internal(cache: &ConfigCache) -> Result<String, ()> {
+    let style = &*cache.style.lock().unwrap();
+    style.to_owned().ok_or(())
+}
+
+fn style_json(style: Result<String, ()>) -> serde_json::Value {
+    match style {
+        Ok(content) => {
+            json!({
+                "valid": true,
+                "content": content
+            })
+        },
+        Err(()) => {
+            json!({
+                "valid": false
+            })
         }
-        Some((time_point, _)) => {
-            let dur = modified.duration_since(*time_point);
-            dur.ok()
+    }
+}
+
+const CONFIG_FILES: &[&str] = &["config.json", "style.css"];
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_millis(100);
+const CONFIG_WATCH_RETRY: Duration = Duration::from_secs(1);
+
+fn get_modified(path: &std::path::Path) -> Option<std::time::SystemTime> {
+    path.metadata().and_then(|x| x.modified()).ok()
+}
+
+fn watch_config_files(app_handle: AppHandle) {
+    let config_dirs: Vec<std::path::PathBuf> = get_config_dirs().into_iter()
+        .map(|(_, dir)| dir)
+        .collect();
+
+    if let Some(user_dir) = get_user_config_dir() {
+        let _ = std::fs::create_dir_all(user_dir);
+    }
+
+    let mut iters = vec![];
+    for dir in config_dirs.iter().filter(|x| x.is_dir()) {
+        match FSEventIter::new(&dir.to_string_lossy()) {
+            Ok(iter) => {
+                iters.push((dir, Some(iter), Instant::now()));
+            }
+            Err(err) => {
+                eprintln!("Can't watch {}: {}", dir.display(), err);
+                iters.push((dir, None, Instant::now() + CONFIG_WATCH_RETRY));
+            }
         }
+    }
+
+    let mut explicit_files: Vec<(std::path::PathBuf, Option<std::time::SystemTime>)> = {
+        let config_cache = app_handle.state::<ConfigCache>();
+        get_config_layers(config_cache.cli_config.as_deref()).into_iter()
+            .filter(|layer| !config_dirs.iter().any(|dir| layer.path.parent() == Some(dir)))
+            .map(|layer| {
+                let modified = get_modified(&layer.path);
+                (layer.path, modified)
+            })
+            .collect()
     };
+    // Folders created later are polled like the explicit files
+    for dir in config_dirs.iter().filter(|x| !x.is_dir()) {
+        for name in CONFIG_FILES {
+            explicit_files.push((dir.join(name), None));
+        }
+    }
 
-    let should_update = match dur {
-        None => {
-            true
+    loop {
+        let mut touched = false;
+        for (dir, iter, retry_at) in iters.iter_mut() {
+            if iter.is_none() && Instant::now() >= *retry_at {
+                *retry_at = Instant::now() + CONFIG_WATCH_RETRY;
+                *iter = FSEventIter::new(&dir.to_string_lossy()).ok();
+                // Changes made while the watcher was down were missed
+                touched |= iter.is_some();
+            }
+            let watcher = match iter {
+                Some(watcher) => watcher,
+                None => {
+                    continue;
+                }
+            };
+
+            let res = watcher.tick();
+            while let Some(event) = watcher.get_event() {
+                if matches!(event, FSEvent::Overflow(_)) ||
+                    event.path().to_str().is_some_and(|name| CONFIG_FILES.contains(&name)) {
+                    touched = true;
+                }
+            }
+            if let Err(err) = res {
+                eprintln!("Can't watch {}: {}", dir.display(), err);
+                let _ = app_handle.emit("watcher-error", json!({ "error": err.to_string() }));
+                *iter = None;
+                *retry_at = Instant::now() + CONFIG_WATCH_RETRY;
+            }
         }
-        Some(dur) => {
-            !dur.is_zero()
+        for (path, modified) in explicit_files.iter_mut() {
+            let new_modified = get_modified(path);
+            if new_modified != *modified {
+                *modified = new_modified;
+                touched = true;
+            }
         }
-    };
 
-    if should_update {
-        let config_content = std::fs::read_to_string(&config)
-            .map_err(|_| ())?;
-        *config_obj = Some((modified, config_content));
+        if touched {
+            let config_cache = app_handle.state::<ConfigCache>();
+            if let Some(loaded) = reload_config(&config_cache) {
+                let registry = app_handle.state::<TrackerRegistry>();
+                registry.set_watch_options(loaded.config.watch_options());
+                registry.set_search_options(loaded.config.search_options());
+                registry.set_grace_periods(loaded.config.grace_periods());
+                registry.set_slot_limits(loaded.config.slot_limits());
+                sync_shelf_windows(&app_handle);
+                let _ = app_handle.emit("config-changed", loaded);
+            }
+            if let Some(style) = reload_style(&config_cache) {
+                let _ = app_handle.emit("style-changed", style_json(style.ok_or(())));
+            }
+        }
+        std::thread::sleep(CONFIG_WATCH_INTERVAL);
     }
+}
 
-    let content = match config_obj {
-        None => "{}".to_owned(),
-        Some((_, content)) => content.to_owned()
-    };
-
-    return Ok(content);
+fn read_register_options(cache: &ConfigCache) -> RegisterOptions {
+    let config = read_config_internal(cache).config;
+    RegisterOptions {
+        content_hash: config.content_hash,
+        watch_scope: config.watch_scope()
+    }
 }
 
-static STYLE: Mutex<Option<(SystemTime, String)>> = Mutex::new(None);
-fn read_style_internal() -> Result<(bool, String), ()> {
-    let style = get_config_file_path("style.css")?;
-    let meta = style.metadata()
-        .map_err(|_| ())?;
+fn read_recovery_roots(cache: &ConfigCache) -> Vec<String> {
+    let mut roots = read_config_internal(cache).config.recovery_roots;
+    if let Some(home) = env_home::env_home_dir() {
+        roots.push(home.to_string_lossy().into_owned());
+    }
+    roots
+}
 
-    let modified = meta.modified()
-        .map_err(|_| ())?;
+fn sync_shelf_windows(app_handle: &AppHandle) {
+    let config_cache = app_handle.state::<ConfigCache>();
+    let config = read_config_internal(&config_cache).config;
+    let shelves = config.shelf_names();
 
-    let style_obj = &mut *STYLE.lock().unwrap();
-    let dur = match style_obj {
-        None => {
-            None
+    for (label, window) in app_handle.webview_windows() {
+        let stale = label.strip_prefix(SHELF_WINDOW_PREFIX)
+            .is_some_and(|shelf| !shelves.iter().any(|x| x == shelf));
+        if stale {
+            let _ = window.destroy();
         }
-        Some((time_point, _)) => {
-            let dur = modified.duration_since(*time_point);
-            dur.ok()
+    }
+
+    let registry = app_handle.state::<TrackerRegistry>();
+    for err in registry.restore(&shelves, &read_register_options(&config_cache)) {
+        eprintln!("Can't restore the bin: {}", err);
+    }
+
+    for shelf in shelves.iter().filter(|x| *x != DEFAULT_SHELF) {
+        let label = get_shelf_label(shelf);
+        if app_handle.get_webview_window(&label).is_some() {
+            continue;
         }
-    };
 
-    let should_update = match dur {
-        None => {
-            true
+        let mut builder = tauri::WebviewWindowBuilder::new(app_handle, &label, tauri::WebviewUrl::App("index.html".into()))
+            .title(format!("side_bin - {}", shelf))
+            .decorations(false)
+            .always_on_top(true)
+            .resizable(false)
+            .skip_taskbar(true)
+            .inner_size(800.0, 600.0);
+        if let Some([x, y]) = config.shelves.get(shelf).and_then(|x| x.position) {
+            builder = builder.position(x, y);
         }
-        Some(dur) => {
-            !dur.is_zero()
+        if let Err(err) = builder.build() {
+            eprintln!("Can't create the window for shelf \"{}\": {}", shelf, err);
         }
-    };
+    }
+}
 
-    if should_update {
-        let style_content = std::fs::read_to_string(&style)
-            .map_err(|_| ())?;
-        *style_obj = Some((modified, style_content));
+#[tauri::command]
+fn show_shelf(app: AppHandle, shelf: &str) {
+    if let Some(window) = app.get_webview_window(&get_shelf_label(shelf)) {
+        let _ = window.show();
+        let _ = window.set_focus();
     }
+}
 
-    match style_obj {
-        None => Err(()),
-        Some((_, content)) => {
-            Ok((should_update, content.to_owned()))
-        }
+#[tauri::command]
+fn hide_shelf(app: AppHandle, shelf: &str) {
+    if let Some(window) = app.get_webview_window(&get_shelf_label(shelf)) {
+        let _ = window.hide();
     }
 }
 
 #[tauri::command]
-fn read_config() -> String {
-    let content = read_config_internal();
-    match content {
-        Ok(s) => s,
-        Err(()) => "{}".into()
+fn switch_shelf(app: AppHandle, config_cache: State<ConfigCache>, shelf: &str) {
+    let shelves = read_config_internal(&config_cache).config.shelf_names();
+    for other in shelves.iter().filter(|x| *x != shelf) {
+        hide_shelf(app.clone(), other);
     }
+    show_shelf(app, shelf);
+}
+
+#[derive(Default)]
+struct RecoveryJob {
+    cancel: AtomicBool
 }
 
 #[tauri::command]
-fn read_style() -> String {
-    let content = read_style_internal();
-    let json = match content {
-        Ok((changed, content)) => {
-            json!({
-                "valid": true,
-                "changed": changed,
-                "content": content
-            })
-        },
-        Err(()) => {
+fn cancel_recovery(recovery_job: State<RecoveryJob>) {
+    recovery_job.cancel.store(true, Ordering::Relaxed);
+}
+
+#[tauri::command]
+fn read_config(window: WebviewWindow, config_cache: State<ConfigCache>) -> String {
+    let loaded = read_config_internal(&config_cache);
+    json!(loaded.config.for_shelf(&get_shelf(&window))).to_string()
+}
+
+#[tauri::command]
+fn get_config(window: WebviewWindow, config_cache: State<ConfigCache>) -> String {
+    let mut loaded = read_config_internal(&config_cache);
+    loaded.config = loaded.config.for_shelf(&get_shelf(&window));
+    json!(loaded).to_string()
+}
+
+#[tauri::command]
+fn config_sources(config_cache: State<ConfigCache>) -> String {
+    let layers: Vec<serde_json::Value> = get_config_layers(config_cache.cli_config.as_deref()).into_iter()
+        .map(|layer| {
             json!({
-                "valid": false
+                "name": layer.name,
+                "path": layer.path,
+                "exists": layer.path.exists()
             })
-        }
-    };
-    json.to_string()
+        })
+        .collect();
+
+    let loaded = read_config_internal(&config_cache);
+    json!({
+        "layers": layers,
+        "sources": loaded.sources
+    }).to_string()
+}
+
+#[tauri::command]
+fn read_style(config_cache: State<ConfigCache>) -> String {
+    let content = read_style_internal(&config_cache);
+    style_json(content).to_string()
 }
 
 #[tauri::command]
@@ -205,26 +466,61 @@ fn get_file_tag(file: &str) -> String {
 }
 
 #[tauri::command]
-fn monitor_command(action: &str, file: &str) -> String {
-    match action {
-        "tick" => {
-            tick();
+async fn get_folder_stats(path: String) -> String {
+    let stats = get_dir_stats(std::path::Path::new(&path), DEFAULT_STATS_LIMIT);
+    let response = match stats {
+        Some(stats) => {
+            json!({
+                "valid": true,
+                "items": stats.items,
+                "size": stats.size,
+                "complete": stats.complete
+            })
         }
+        None => {
+            json!({
+                "valid": false
+            })
+        }
+    };
+
+    response.to_string()
+}
+
+#[tauri::command(async)]
+fn monitor_command(
+    window: WebviewWindow,
+    registry: State<TrackerRegistry>,
+    config_cache: State<ConfigCache>,
+    action: String,
+    file: String) -> String {
+    match action.as_str() {
         "register" => {
-            let file_id = fs_mon::trackers::register_file(file);
-            let file_id = json!({
-                "id": file_id
-            });
+            let options = read_register_options(&config_cache);
+            let res = registry.register(&file, &get_shelf(&window), &options);
+            let file_id = match res {
+                Ok((file_id, weak)) => {
+                    json!({
+                        "id": file_id,
+                        "weak": weak
+                    })
+                }
+                Err(err) => {
+                    json!({
+                        "error": err.to_string()
+                    })
+                }
+            };
             return file_id.to_string();
         }
         "unregister" => {
-            fs_mon::trackers::unregister_file(file);
+            registry.unregister(&file);
             return json!({
                 "unregistered": file
             }).to_string();
         }
         "update" => {
-            let state = get_tracker_state(file);
+            let state = registry.state(&file);
             match state {
                 Some(state) => {
                     let state_json = serde_json::to_string(&state).unwrap();
@@ -240,11 +536,117 @@ fn monitor_command(action: &str, file: &str) -> String {
     "{}".into()
 }
 
-// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+fn emit_state_change(app: &AppHandle, change: Option<StateChange>) -> serde_json::Value {
+    let change = match change {
+        Some(change) => change,
+        None => {
+            return json!({});
+        }
+    };
+    let response = json!({
+        "state": change.new
+    });
+    let _ = app.emit("tracker-state-changed", change);
+    response
+}
+
+#[tauri::command(async)]
+fn file_command(
+    app: AppHandle,
+    registry: State<TrackerRegistry>,
+    config_cache: State<ConfigCache>,
+    action: String,
+    id: String,
+    target: String) -> String {
+    let options = read_register_options(&config_cache);
+    let res = match action.as_str() {
+        "copy" => {
+            registry.copy_file(&id, &target).map(|path| json!({ "copied": path }))
+        }
+        "move" => {
+            registry.move_file(&id, &target, &options).map(|(change, warning)| {
+                let mut response = emit_state_change(&app, change);
+                if let Some(warning) = warning {
+                    response["warning"] = json!(warning.to_string());
+                }
+                response
+            })
+        }
+        "rename" => {
+            registry.rename_file(&id, &target, &options).map(|change| emit_state_change(&app, change))
+        }
+        "trash" => {
+            registry.trash_file(&id).map(|change| emit_state_change(&app, change))
+        }
+        "confirm" => {
+            registry.confirm(&id).map(|change| emit_state_change(&app, change))
+        }
+        _ => {
+            return json!({
+                "error": format!("Unknown action \"{}\"", action)
+            }).to_string();
+        }
+    };
+
+    let response = match res {
+        Ok(response) => response,
+        Err(err) => {
+            json!({
+                "error": err.to_string()
+            })
+        }
+    };
+    response.to_string()
+}
+
 #[tauri::command]
-fn resize_win(app: AppHandle, x: i32, y: i32, w: u32, h: u32) {
-    let window = app.get_webview_window("main").unwrap();
+fn list_entries(window: WebviewWindow, registry: State<TrackerRegistry>) -> String {
+    json!({
+        "entries": registry.list(Some(&get_shelf(&window)))
+    }).to_string()
+}
+
+#[tauri::command(async)]
+fn register_files(
+    window: WebviewWindow,
+    registry: State<TrackerRegistry>,
+    config_cache: State<ConfigCache>,
+    files: Vec<String>) -> String {
+    let options = read_register_options(&config_cache);
+    let results = registry.register_files(&files, &get_shelf(&window), &options);
+    register_results_json(&files, results).to_string()
+}
+
+fn handle_rpc_call(app_handle: &AppHandle, method: &str, params: &serde_json::Value) ->
+    Result<serde_json::Value, RpcError> {
+    let registry = app_handle.state::<TrackerRegistry>();
+    let config_cache = app_handle.state::<ConfigCache>();
+    let options = read_register_options(&config_cache);
+    let shelves = read_config_internal(&config_cache).config.shelf_names();
+
+    let result = ipc::dispatch(&registry, &options, &shelves, method, params)?;
+    match method {
+        "register" => {
+            let added: Vec<serde_json::Value> = result["results"].as_array()
+                .into_iter()
+                .flatten()
+                .filter(|x| x.get("id").is_some())
+                .cloned()
+                .collect();
+            let shelf = result["shelf"].as_str().unwrap_or(DEFAULT_SHELF);
+            let _ = app_handle.emit_to(get_shelf_label(shelf), "entries-added", added);
+        }
+        "unregister" => {
+            let _ = app_handle.emit("entries-removed", &result["removed"]);
+        }
+        _ => {}
+    }
+    Ok(result)
+}
 
+// Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
+#[tauri::command]
+fn resize_win(window: WebviewWindow, x: i32, y: i32, w: u32, h: u32) {
     let pos = tauri::Position::Physical(tauri::PhysicalPosition {
         x,
         y
@@ -259,8 +661,7 @@ fn resize_win(app: AppHandle, x: i32, y: i32, w: u32, h: u32) {
 }
 
 #[tauri::command]
-fn get_win_pos(app: AppHandle) -> String {
-    let window = app.get_webview_window("main").unwrap();
+fn get_win_pos(window: WebviewWindow) -> String {
     if let Ok(pos) = window.outer_position() {
         return json!({
             "valid": true,
@@ -296,7 +697,7 @@ fn get_file_icon(file: &str) -> String {
         icon.height,
         image::ExtendedColorType::Rgba8);
 
-    if let Err(_) = write_res {
+    if write_res.is_err() {
         return res;
     }
 
@@ -306,31 +707,174 @@ fn get_file_icon(file: &str) -> String {
     }).to_string()
 }
 
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+const SEARCH_WAIT: Duration = Duration::from_secs(1);
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     env::set_var("RUST_BACKTRACE", "1");
     panic::set_hook(Box::new(|_| {
         let backtrace = std::backtrace::Backtrace::capture();
-        let info = format!("{}", backtrace.to_string());
+        let info = backtrace.to_string();
         let _ = std::fs::write("crash_dump.dmp", info);
     }));
 
-    let running_instance = fs_mon::fs_mon::get_running_instance();
-    if running_instance > 0 {
-        println!("App already running at PID: {}", running_instance);
-        return;
+    let args: Vec<String> = env::args().collect();
+    let args = match parse_args(&args) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+    if let Some(profile) = &args.profile {
+        if !instance::is_valid_profile(profile) {
+            eprintln!("Invalid profile name \"{}\": use letters, digits, '-' and '_'", profile);
+            std::process::exit(2);
+        }
+    }
+    if let Some(shelf) = &args.shelf {
+        if !is_valid_shelf_name(shelf) {
+            eprintln!("Invalid shelf name \"{}\": use letters, digits, '-' and '_'", shelf);
+            std::process::exit(2);
+        }
+    }
+
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
     }
 
+    let instance_lock = match instance::acquire(args.profile.as_deref()) {
+        Ok(instance_lock) => instance_lock,
+        Err(LockError::Running(info)) => {
+            println!("App already running at PID: {} ({})", info.pid, info.socket);
+            return;
+        }
+        Err(LockError::Io(err)) => {
+            eprintln!("Can't acquire the instance lock: {}", err);
+            return;
+        }
+    };
+    let socket = match instance::get_socket_path(args.profile.as_deref()) {
+        Ok(socket) => socket,
+        Err(err) => {
+            eprintln!("Can't locate the control socket: {}", err);
+            return;
+        }
+    };
+
+    let registry = match get_bin_file_path(args.profile.as_deref()) {
+        Some(path) => TrackerRegistry::with_persistence(path),
+        None => TrackerRegistry::new()
+    };
+
     tauri::Builder::default()
+    .manage(registry)
+    .manage(ConfigCache {
+        cli_config: args.config,
+        ..Default::default()
+    })
+    .manage(RecoveryJob::default())
+    .setup(move |app| {
+        let config_cache = app.state::<ConfigCache>();
+        reload_config(&config_cache);
+        reload_style(&config_cache);
+        let config = read_config_internal(&config_cache).config;
+        app.state::<TrackerRegistry>().set_watch_options(config.watch_options());
+        app.state::<TrackerRegistry>().set_search_options(config.search_options());
+        app.state::<TrackerRegistry>().set_grace_periods(config.grace_periods());
+        app.state::<TrackerRegistry>().set_slot_limits(config.slot_limits());
+
+        let app_handle = app.handle().clone();
+        std::thread::spawn(move || {
+            watch_config_files(app_handle);
+        });
+
+        let app_handle = app.handle().clone();
+        std::thread::spawn(move || {
+            let subscribe_handle = app_handle.clone();
+            let res = ipc::serve(
+                &socket,
+                move |method, params| handle_rpc_call(&app_handle, method, params),
+                move || subscribe_handle.state::<TrackerRegistry>().subscribe());
+            if let Err(err) = res {
+                eprintln!("Can't start the IPC server: {}", err);
+            }
+        });
+
+        sync_shelf_windows(app.handle());
+
+        // Reset before the thread starts so a cancel sent while it starts
+        // up still stops the search.
+        app.state::<RecoveryJob>().cancel.store(false, Ordering::Relaxed);
+        let app_handle = app.handle().clone();
+        std::thread::spawn(move || {
+            let registry = app_handle.state::<TrackerRegistry>();
+            let config_cache = app_handle.state::<ConfigCache>();
+            let recovery_job = app_handle.state::<RecoveryJob>();
+            let changes = registry.recover(
+                &read_recovery_roots(&config_cache),
+                &read_register_options(&config_cache),
+                &recovery_job.cancel,
+                |progress| {
+                    let _ = app_handle.emit("recovery-progress", progress);
+                });
+
+            for change in changes {
+                let _ = app_handle.emit("tracker-state-changed", change);
+            }
+            let _ = app_handle.emit("recovery-finished", ());
+        });
+
+        let app_handle = app.handle().clone();
+        std::thread::spawn(move || {
+            loop {
+                let registry = app_handle.state::<TrackerRegistry>();
+                let res = registry.tick();
+                for change in res.changes {
+                    let _ = app_handle.emit("tracker-state-changed", change);
+                }
+                for err in res.errors {
+                    eprintln!("{}", err);
+                    let _ = app_handle.emit("watcher-error", json!({ "error": err.to_string() }));
+                }
+                std::thread::sleep(TICK_INTERVAL);
+            }
+        });
+
+        let app_handle = app.handle().clone();
+        std::thread::spawn(move || {
+            loop {
+                let registry = app_handle.state::<TrackerRegistry>();
+                let config_cache = app_handle.state::<ConfigCache>();
+                let changes = registry.run_searches(&read_register_options(&config_cache), SEARCH_WAIT);
+                for change in changes {
+                    let _ = app_handle.emit("tracker-state-changed", change);
+                }
+            }
+        });
+        Ok(())
+    })
     .plugin(tauri_plugin_opener::init())
     .plugin(tauri_plugin_drag::init())
+    .plugin(tauri_plugin_dialog::init())
     .invoke_handler(
         tauri::generate_handler![
             resize_win,
             get_win_pos,
             monitor_command,
+            file_command,
+            register_files,
+            list_entries,
+            cancel_recovery,
+            show_shelf,
+            hide_shelf,
+            switch_shelf,
             get_file_tag,
+            get_folder_stats,
             read_config,
+            get_config,
+            config_sources,
             read_style,
             get_file_icon,
             open_file_directory,
@@ -338,5 +882,29 @@ pub fn run() {
         ])
     .run(tauri::generate_context!())
     .unwrap();
+
+    drop(instance_lock);
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        std::iter::once("side_bin").chain(args.iter().copied()).map(String::from).collect()
+    }
+
+    #[test]
+    fn options_do_not_take_flags_or_commands() {
+        let res = parse_args(&args(&["--new-instance", "work", "--shelf=review", "list"])).unwrap();
+        assert_eq!(res.profile.as_deref(), Some("work"));
+        assert_eq!(res.shelf.as_deref(), Some("review"));
+        assert_eq!(res.positional, ["list"]);
+
+        assert!(parse_args(&args(&["--new-instance", "list"])).is_err());
+        assert!(parse_args(&args(&["--new-instance", "--shelf", "review"])).is_err());
+        assert!(parse_args(&args(&["--config", "--new-instance=work"])).is_err());
+        assert!(parse_args(&args(&["list", "--shelf"])).is_err());
+    }
+}
@@ -30,14 +30,9 @@ async function openFileDir(file) {
     });
 }
 
-async function readConfig() {
-    const config = await invoke("read_config", { });
-    let configJSON = {};
-    try {
-        configJSON = JSON.parse(config);
-    }
-    catch(_) { }
-    return configJSON;
+async function getConfig() {
+    const response = await invoke("get_config", { });
+    return JSON.parse(response);
 }
 
 async function readStyle() {
@@ -48,13 +43,7 @@ async function readStyle() {
     }
     catch(_) { }
 
-    if (!styleJSON.valid) {
-        return;
-    }
-    if (!styleJSON.changed) {
-        return;
-    }
-    return styleJSON.content;
+    return styleJSON;
 }
 
 async function getFileTag(file) {
@@ -95,6 +84,56 @@ async function monitorCommand(action, file) {
     return response;
 }
 
+async function fileCommand(action, id, target) {
+    let response = await invoke("file_command", {
+        action,
+        id,
+        target: target || ""
+    });
+
+    response = JSON.parse(response);
+
+    return response;
+}
+
+async function pickDirectory() {
+    const { open } = window.__TAURI__.dialog;
+    return await open({
+        directory: true,
+        multiple: false
+    });
+}
+
+async function registerFiles(files) {
+    let response = await invoke("register_files", {
+        files
+    });
+
+    response = JSON.parse(response);
+
+    return response.results;
+}
+
+async function getFolderStats(path) {
+    let response = await invoke("get_folder_stats", {
+        path
+    });
+
+    response = JSON.parse(response);
+
+    return response;
+}
+
+function formatSize(size) {
+    const units = ["B", "KB", "MB", "GB", "TB"];
+    let unit = 0;
+    while (size >= 1024 && unit < units.length - 1) {
+        size /= 1024;
+        unit++;
+    }
+    return `${unit === 0 ? size : size.toFixed(1)} ${units[unit]}`;
+}
+
 async function getIcon(file) {
     let response = await invoke("get_file_icon", {
         file
@@ -106,12 +145,51 @@ async function getIcon(file) {
 }
 
 const droppedFiles = {};
-let fileCallbacks = [];
+const stateHandlers = {};
+const recoveryHandlers = {};
+const slotsById = {};
+
+async function listenForStateChanges() {
+    const { listen } = window.__TAURI__.event;
+    await listen("tracker-state-changed", event => {
+        const handler = stateHandlers[event.payload.id];
+        if (handler) {
+            handler(event.payload.new, event.payload.unconfirmed);
+        }
+    });
+    await listen("recovery-progress", event => {
+        const handler = recoveryHandlers[event.payload.id];
+        if (handler) {
+            handler(event.payload);
+        }
+    });
+    await listen("recovery-finished", () => {
+        for (const id in recoveryHandlers) {
+            recoveryHandlers[id]();
+        }
+    });
+    await listen("watcher-error", event => {
+        console.warn(event.payload.error);
+    });
+}
 
-async function registerFile(elem) {
+async function cancelRecovery() {
+    await invoke("cancel_recovery", { });
+}
+
+async function listEntries() {
+    let response = await invoke("list_entries", { });
+
+    response = JSON.parse(response);
+
+    return response.entries;
+}
+
+async function registerFile(elem, entry) {
     const overlay = elem.querySelector("#overlay");
     const name = elem.querySelector("#name");
     const itemIcon = elem.querySelector("#item-icon");
+    const details = elem.querySelector("#details");
 
     const file = elem.storedFile;
     const icon = await getIcon(file);
@@ -128,87 +206,82 @@ async function registerFile(elem) {
         itemIcon.style.backgroundImage = `url('${imageUrl}')`;
     }
 
-    const fileIdObj = await new Promise(async resolve => {
-        async function task() {
-            let fileId = await monitorCommand("register", file);
-            resolve(fileId);
-        }
-        fileCallbacks.push(task);
-    });
+    const fileIdObj = entry ? entry : await monitorCommand("register", file);
     const fileId = fileIdObj.id;
+    if (!fileId) {
+        elem.classList.remove("item-full");
+        overlay.style.display = "none";
+        elem.destroy();
+        return;
+    }
     droppedFiles[fileId] = true;
+    slotsById[fileId] = elem;
+    elem.fileId = fileId;
+
+    if (fileIdObj.weak) {
+        elem.classList.add("item-weak");
+    }
+    if (fileIdObj.unconfirmed) {
+        elem.classList.add("item-unconfirmed");
+    }
 
     let stop = false;
+    let pendingStates = [];
+    let notify;
+
+    stateHandlers[fileId] = (state, unconfirmed) => {
+        elem.classList.toggle("item-unconfirmed", !!unconfirmed);
+        pendingStates.push(state);
+        if (notify) {
+            notify();
+        }
+    };
+    let truncated = [];
+    recoveryHandlers[fileId] = progress => {
+        if (!elem.classList.contains("item-lost")) {
+            return;
+        }
+        if (progress) {
+            truncated = progress.truncated;
+        }
+        if (progress && progress.searched < progress.total) {
+            elem.title = `Searching ${progress.root} (${progress.searched + 1}/${progress.total})...`;
+        }
+        else if (truncated.length > 0) {
+            elem.title = `File moved to unknown location (too many files to search all of ${truncated.join(", ")})`;
+        }
+        else {
+            elem.title = "File moved to unknown location";
+        }
+    };
     elem.closeFunc = () => {
         stop = true;
+        if (notify) {
+            notify();
+        }
     };
 
-    async function checkFileTag() {
-        const newestTag = await new Promise(resolve => {
-            async function task() {
-                if (!elem.storedFile) {
+    async function nextState() {
+        while (!stop && pendingStates.length === 0) {
+            await new Promise(resolve => {
+                notify = () => {
+                    notify = undefined;
                     resolve();
-                    return;
-                }
-                let resp = await getFileTag(elem.storedFile);
-                if (resp.valid) {
-                    resolve(resp.tag);
-                    return;
-                }
-                resolve();
-            }
-            fileCallbacks.push(task);
-        });
-
-        return newestTag === fileId;
-    }
-
-    const tagCheckOk = Symbol("checked");
-    const tagCheckInProgress = Symbol("in progress");
-
-    let tagCheck = tagCheckOk;
-
-    let stopChecks;
-    let tagCheckRoutine = new Promise(resolve => {
-        let goOn = true;
-        stopChecks = () => {
-            goOn = false;
-        };
-
-        function invalidate() {
-            tagCheck = tagCheckInProgress;
-            if (goOn) {
-                setTimeout(invalidate, 1000);
-            }
-            else {
-                resolve();
-            }
-        }
-
-        setTimeout(invalidate, 1000);
-    });
-
-    let age = 0;
-    while (!stop && elem.storedFile) {
-        const state = await new Promise(resolve => {
-            async function task() {
-                let state = await monitorCommand("update", fileId);
-                resolve(state);
-            }
-            fileCallbacks.push(task);
-        });
-
-        if (tagCheck === tagCheckInProgress) {
-            const ok = await checkFileTag();
-            if (ok) {
-                tagCheck = tagCheckOk;
-            }
+                };
+            });
         }
+        return pendingStates.shift();
+    }
 
-        if (state.Certain && tagCheck === tagCheckOk) {
-            age = 0;
+    let state = await monitorCommand("update", fileId);
+    while (!stop) {
+        if (state.Certain) {
+            elem.classList.remove("item-lost");
+            elem.title = "";
             elem.storedFile = state.Certain.path;
-            const lastSlash = elem.storedFile.lastIndexOf("\\");
+            const lastSlash = Math.max(
+                elem.storedFile.lastIndexOf("\\"),
+                elem.storedFile.lastIndexOf("/"));
             if (lastSlash >= 0)
             {
                 let fileName = elem.storedFile.substring(lastSlash + 1);
@@ -218,15 +291,30 @@ async function registerFile(elem) {
                 }
                 name.innerHTML = fileName;
             }
-        }
-        else {
-            if (age > 3) {
-                elem.storedFile = undefined;
+
+            const stats = await getFolderStats(elem.storedFile);
+            if (stats.valid) {
+                const more = stats.complete ? "" : "+";
+                details.innerHTML = `${stats.items}${more} items, ${formatSize(stats.size)}${more}`;
             }
-            ++age;
         }
+        else if (state.Lost) {
+            elem.classList.add("item-lost");
+            elem.title = "File moved to unknown location";
+        }
+        else if (state.Deleted) {
+            elem.storedFile = undefined;
+            elem.classList.remove("item-lost");
+            elem.classList.add("item-deleted");
+            elem.title = "File deleted";
+        }
+
+        state = await nextState();
     }
 
+    delete stateHandlers[fileId];
+    delete recoveryHandlers[fileId];
+    delete slotsById[fileId];
     elem.storedFile = undefined;
     await unregister(fileId);
     elem.classList.remove("item-full");
@@ -234,52 +322,95 @@ async function registerFile(elem) {
     elem.style.backgroundImage = "";
 
     elem.destroy();
-
-    stopChecks();
-    await tagCheckRoutine;
 }
 
 async function unregister(id) {
-    let res = await new Promise(resolve => {
-        fileCallbacks.push(async () => {
-            const response = await monitorCommand("unregister", id);
-            resolve(response);
-        });
-    });
+    const res = await monitorCommand("unregister", id);
     delete droppedFiles[id];
 
     return res;
 }
 
-async function mainTick() {
-    async function tick() {
-        const funcs = fileCallbacks;
-        fileCallbacks = [];
+async function showShelf(shelf) {
+    await invoke("show_shelf", {
+        shelf
+    });
+}
 
-        const promises = funcs.map(f => {
-            return f();
-        });
-        await Promise.all(promises);
+async function hideShelf(shelf) {
+    await invoke("hide_shelf", {
+        shelf
+    });
+}
 
-        await monitorCommand("tick", "");
-    }
-    while (true) {
-        await tick();
-        await new Promise(resolve => {
-            setTimeout(() => {
-                resolve();
-            }, 10);
-        });
+async function switchShelf(shelf) {
+    await invoke("switch_shelf", {
+        shelf
+    });
+}
+
+function isMainWindow() {
+    const { getCurrentWebviewWindow } = window.__TAURI__.webviewWindow;
+    return getCurrentWebviewWindow().label === "main";
+}
+
+function getShelfNames() {
+    const names = ["default"];
+    for (const name in config.shelves) {
+        if (name !== "default") {
+            names.push(name);
+        }
     }
+    return names;
 }
 
-async function setupTray() {
-    const { TrayIcon } = window.__TAURI__.tray;
-    const { defaultWindowIcon } = window.__TAURI__.app;
+async function createTrayMenu() {
     const { Menu } = window.__TAURI__.menu;
 
-    const menu = await Menu.new({
+    const shelfItems = [];
+    const shelves = getShelfNames();
+    if (shelves.length > 1) {
+        for (const shelf of shelves) {
+            shelfItems.push({
+                id: `shelf-${shelf}`,
+                text: `Shelf: ${shelf}`,
+                items: [
+                    {
+                        id: `show-shelf-${shelf}`,
+                        text: 'Show',
+                        action: () => {
+                            showShelf(shelf);
+                        }
+                    },
+                    {
+                        id: `hide-shelf-${shelf}`,
+                        text: 'Hide',
+                        action: () => {
+                            hideShelf(shelf);
+                        }
+                    },
+                    {
+                        id: `switch-shelf-${shelf}`,
+                        text: 'Show Only This Shelf',
+                        action: () => {
+                            switchShelf(shelf);
+                        }
+                    }
+                ]
+            });
+        }
+    }
+
+    return await Menu.new({
         items: [
+            ...shelfItems,
+            {
+                id: 'cancel-recovery',
+                text: 'Stop Searching for Lost Files',
+                action: () => {
+                    cancelRecovery();
+                }
+            },
             {
                 id: 'quit',
                 text: 'Quit',
@@ -289,65 +420,100 @@ async function setupTray() {
             }
         ]
     });
+}
+
+let tray;
+let trayShelves;
 
+async function setupTray() {
+    const { TrayIcon } = window.__TAURI__.tray;
+    const { defaultWindowIcon } = window.__TAURI__.app;
+
+    trayShelves = JSON.stringify(getShelfNames());
     let options = {
-        menu,
+        menu: await createTrayMenu(),
         menuOnLeftClick: true,
         icon: await defaultWindowIcon(),
     };
     
-    await TrayIcon.new(options);
+    tray = await TrayIcon.new(options);
 }
 
-async function updateConfig() {
-    config = await readConfig();
-
-    if (!config.anchor) {
-        config.anchor = [0, 0];
-    }
-    if (!config.position) {
-        config.position = [0, 0];
+async function updateTray() {
+    const shelves = JSON.stringify(getShelfNames());
+    if (!tray || shelves === trayShelves) {
+        return;
     }
-    if (!config.expanded) {
-        config.expanded = [100, 300];
+    trayShelves = shelves;
+    await tray.setMenu(await createTrayMenu());
+}
+
+let lastConfigIssues;
+
+function reportConfigIssues(loaded) {
+    const issues = JSON.stringify([loaded.errors, loaded.warnings]);
+    if (issues === lastConfigIssues) {
+        return;
     }
-    if (!config.collapsed) {
-        config.collapsed = [20, 20];
+    lastConfigIssues = issues;
+
+    for (const error of loaded.errors) {
+        console.error(`config.json: ${error.key}: ${error.message}`);
     }
-    if (!config.nameLimit) {
-        config.nameLimit = 30;
+    for (const warning of loaded.warnings) {
+        console.warn(`config.json: ${warning.key}: ${warning.message}`);
     }
+}
+
+function applyConfig(loaded) {
+    reportConfigIssues(loaded);
+    config = loaded.config;
 
     return config;
 }
 
+async function updateConfig() {
+    return applyConfig(await getConfig());
+}
+
 window.addEventListener("DOMContentLoaded", async () => {
-    await setupTray();
+    const { listen } = window.__TAURI__.event;
 
     let config = await updateConfig();
-    async function refreshConfig() {
+    await listen("config-changed", async () => {
         config = await updateConfig();
-        setTimeout(refreshConfig, 1000);
+        if (isMainWindow()) {
+            await updateTray();
+        }
+    });
+
+    if (isMainWindow()) {
+        await setupTray();
     }
-    setTimeout(refreshConfig, 1000);
 
     let cssBlob;
-    async function refreshStyle() {
-        const css = await readStyle();
-        if (css) {
-            cssBlob = new Blob(
-                [css],
-                {
-                    type: "text/css"
-                }
-            );
-            const url = URL.createObjectURL(cssBlob);
-            const cssElem = document.querySelector("#dynamic_style");
-            cssElem.href = url;
+    function applyStyle(style) {
+        const cssElem = document.querySelector("#dynamic_style");
+        if (cssBlob) {
+            URL.revokeObjectURL(cssElem.href);
+            cssBlob = undefined;
         }
-        setTimeout(refreshStyle, 1000);
+        if (!style.valid) {
+            cssElem.removeAttribute("href");
+            return;
+        }
+        cssBlob = new Blob(
+            [style.content],
+            {
+                type: "text/css"
+            }
+        );
+        cssElem.href = URL.createObjectURL(cssBlob);
     }
-    refreshStyle();
+    applyStyle(await readStyle());
+    await listen("style-changed", event => {
+        applyStyle(event.payload);
+    });
 
     const bin = document.querySelector("bin");
 
@@ -359,7 +525,7 @@ window.addEventListener("DOMContentLoaded", async () => {
         });
     }
     
-    mainTick();
+    await listenForStateChanges();
 
     const expanded = Symbol("expanded");
     const collapsed = Symbol("collapsed");
@@ -512,6 +678,7 @@ window.addEventListener("DOMContentLoaded", async () => {
             <div class="slot-overlay" id="overlay">
             <div class="item-icon" id="item-icon"></div>
             <div class="name" id="name"></div>
+            <div class="details" id="details"></div>
             <div class="close" id="close"></div>
             </div>
             </slot>
@@ -532,27 +699,35 @@ window.addEventListener("DOMContentLoaded", async () => {
     const spacer = document.querySelector("spc");
 
     async function dropFileInBin(payload) {
-        const paths = payload.paths;
-        const file = paths[0];
+        const numSlots = container.querySelectorAll("slot").length;
+        const freeSlots = config.maxSlots - numSlots;
 
-        if (paths.length > 1) {
-            return;
-        }
-        if (file.startsWith("\\\\")) {
-            return;
-        }
-        {
-            const numSlots = container.querySelectorAll("slot").length;
-            if (numSlots >= 3) {
-                return;
+        const files = [];
+        for (const file of payload.paths) {
+            if (files.length >= freeSlots) {
+                break;
+            }
+
+            let resp = await getFileTag(file);
+            if (resp.valid && droppedFiles[resp.tag]) {
+                continue;
             }
+            files.push(file);
         }
 
-        let resp = await getFileTag(file);
-        if (resp.valid && droppedFiles[resp.tag]) {
+        if (files.length === 0) {
             return;
         }
 
+        const results = await registerFiles(files);
+        for (const res of results) {
+            if (res.id) {
+                addItem(res.file, res);
+            }
+        }
+    }
+
+    function addItem(file, entry) {
         const item = createSlot();
         item.storedFile = file;
         item.destroy = () => {
@@ -560,14 +735,14 @@ window.addEventListener("DOMContentLoaded", async () => {
 
             const numSlots = container.querySelectorAll("slot").length;
             container.style.flex = numSlots;
-            spacer.style.flex = 3 - numSlots;
+            spacer.style.flex = Math.max(config.maxSlots - numSlots, 0);
         };
 
         const numSlots = container.querySelectorAll("slot").length;
         container.style.flex = numSlots;
-        spacer.style.flex = 3 - numSlots;
+        spacer.style.flex = Math.max(config.maxSlots - numSlots, 0);
 
-        registerFile(item);
+        registerFile(item, entry);
         let overlay = item.querySelector("#overlay");
         overlay.style.display = "";
 
@@ -577,8 +752,116 @@ window.addEventListener("DOMContentLoaded", async () => {
             }
         });
 
-        item.addEventListener("mousedown", async () => {
-            if (!item.storedFile) {
+        async function runFileCommand(action, target) {
+            const response = await fileCommand(action, item.fileId, target);
+            if (response.error) {
+                console.error(`${action}: ${response.error}`);
+                item.title = response.error;
+            }
+            else if (response.warning) {
+                console.warn(`${action}: ${response.warning}`);
+                item.title = response.warning;
+            }
+        }
+
+        function startRename() {
+            const name = item.querySelector("#name");
+            const fileName = item.storedFile.substring(Math.max(
+                item.storedFile.lastIndexOf("\\"),
+                item.storedFile.lastIndexOf("/")) + 1);
+            const shownName = name.innerHTML;
+
+            name.textContent = fileName;
+            name.contentEditable = "true";
+            name.focus();
+
+            let done = false;
+            async function finish(commit) {
+                if (done) {
+                    return;
+                }
+                done = true;
+                name.contentEditable = "false";
+                const newName = name.textContent.trim();
+                name.innerHTML = shownName;
+                if (commit && newName && newName !== fileName) {
+                    await runFileCommand("rename", newName);
+                }
+            }
+            name.addEventListener("keydown", evt => {
+                if (evt.key === "Enter") {
+                    evt.preventDefault();
+                    finish(true);
+                }
+                else if (evt.key === "Escape") {
+                    finish(false);
+                }
+            });
+            name.addEventListener("blur", () => {
+                finish(false);
+            }, { once: true });
+        }
+
+        item.addEventListener("contextmenu", async evt => {
+            evt.preventDefault();
+            if (!item.storedFile || !item.fileId) {
+                return;
+            }
+
+            const { Menu } = window.__TAURI__.menu;
+            const confirmItems = item.classList.contains("item-unconfirmed") ? [
+                {
+                    id: 'confirm',
+                    text: 'Confirm Match',
+                    action: async () => {
+                        await runFileCommand("confirm");
+                    }
+                }
+            ] : [];
+            const menu = await Menu.new({
+                items: [
+                    ...confirmItems,
+                    {
+                        id: 'copy-to',
+                        text: 'Copy To...',
+                        action: async () => {
+                            const dir = await pickDirectory();
+                            if (dir) {
+                                await runFileCommand("copy", dir);
+                            }
+                        }
+                    },
+                    {
+                        id: 'move-to',
+                        text: 'Move To...',
+                        action: async () => {
+                            const dir = await pickDirectory();
+                            if (dir) {
+                                await runFileCommand("move", dir);
+                            }
+                        }
+                    },
+                    {
+                        id: 'rename',
+                        text: 'Rename',
+                        action: () => {
+                            startRename();
+                        }
+                    },
+                    {
+                        id: 'trash',
+                        text: 'Move to Trash',
+                        action: async () => {
+                            await runFileCommand("trash");
+                        }
+                    }
+                ]
+            });
+            await menu.popup();
+        });
+
+        item.addEventListener("mousedown", async evt => {
+            if (!item.storedFile || evt.button !== 0 || evt.target.isContentEditable) {
                 return;
             }
             let mouseupHandler, mousemoveHandler;
@@ -609,6 +892,32 @@ window.addEventListener("DOMContentLoaded", async () => {
         });
     }
 
+    const entries = await listEntries();
+    for (const entry of entries) {
+        droppedFiles[entry.id] = true;
+        addItem(entry.path, entry);
+    }
+
+    await listen("entries-added", event => {
+        for (const entry of event.payload) {
+            if (droppedFiles[entry.id]) {
+                continue;
+            }
+            if (container.querySelectorAll("slot").length >= config.maxSlots) {
+                console.error(`No free slot for ${entry.file}`);
+                break;
+            }
+            droppedFiles[entry.id] = true;
+            addItem(entry.file, entry);
+        }
+    });
+    await listen("entries-removed", event => {
+        const slot = slotsById[event.payload];
+        if (slot && slot.closeFunc) {
+            slot.closeFunc();
+        }
+    });
+
     let webview = window.__TAURI__.webview;
     const unlisten = await webview
         .getCurrentWebview()